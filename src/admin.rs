@@ -0,0 +1,243 @@
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap},
+    Json,
+};
+use chrono::{TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, sync::Arc};
+use tracing::warn;
+
+use crate::{db_client, log_client, providers, AppState, ErrorResponse};
+
+// Admin endpoints are disabled unless this file holds a token, separate from the chat access_token.txt
+const ADMIN_TOKEN_FILE: &str = "admin_token.txt";
+
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 500;
+
+// How far back /admin/tokens looks in LOGS for each token's latest error
+const RECENT_ERROR_HOURS: i64 = 24;
+
+/// Check the admin token from an `Authorization: Bearer ...` header or an `admin_token` query parameter.
+pub fn check_admin_token(headers: &HeaderMap, query_token: Option<&str>) -> Result<(), ErrorResponse> {
+    let required_token = match fs::read_to_string(ADMIN_TOKEN_FILE) {
+        Ok(token) => token.trim().to_string(),
+        Err(_) => "".to_string(),
+    };
+    if required_token.is_empty() {
+        return Err(ErrorResponse {
+            error: format!("Admin API is disabled, put a token in {} to enable it", ADMIN_TOKEN_FILE),
+        });
+    }
+
+    let header_token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match header_token.or(query_token) {
+        Some(token) if token.trim() == required_token => Ok(()),
+        _ => {
+            warn!("Invalid or missing admin token provided in request");
+            Err(ErrorResponse { error: "Invalid or missing admin token".to_string() })
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LogsQuery {
+    from: Option<String>,       // Inclusive, "YYYY-MM-DD" or "YYYY-MM-DD HH:MM:SS" server local time
+    to: Option<String>,         // Inclusive, same format as `from`
+    token_type: Option<String>,
+    token_id: Option<i64>,
+    caller: Option<String>,
+    request_id: Option<String>,
+    success: Option<bool>,
+    q: Option<String>,          // Text search in prompts and responses
+    page: Option<u32>,          // 1-based
+    per_page: Option<u32>,
+    admin_token: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct LogsResponse {
+    total: i64,
+    page: u32,
+    per_page: u32,
+    logs: Vec<log_client::LogEntry>,
+}
+
+fn non_empty(value: Option<String>) -> Option<String> {
+    value.map(|v| v.trim().to_string()).filter(|v| !v.is_empty())
+}
+
+// GET /admin/logs
+pub async fn handle_get_logs(
+    State(_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(params): Query<LogsQuery>,
+) -> Json<Result<LogsResponse, ErrorResponse>> {
+    if let Err(e) = check_admin_token(&headers, params.admin_token.as_deref()) {
+        return Json(Err(e));
+    }
+
+    let page = params.page.unwrap_or(1).max(1);
+    let per_page = params.per_page.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    // A bare date for `to` should include the whole day
+    let to = non_empty(params.to).map(|to| if to.len() == 10 { format!("{} 23:59:59", to) } else { to });
+
+    let filter = log_client::LogFilter {
+        from: non_empty(params.from),
+        to,
+        token_type: non_empty(params.token_type),
+        token_id: params.token_id,
+        caller: non_empty(params.caller),
+        request_id: non_empty(params.request_id),
+        success: params.success,
+        text: non_empty(params.q),
+        limit: per_page,
+        offset: (page - 1).saturating_mul(per_page),
+    };
+
+    let result = log_client::open_default().and_then(|client| client.query_logs(&filter));
+    match result {
+        Ok((logs, total)) => Json(Ok(LogsResponse { total, page, per_page, logs })),
+        Err(e) => Json(Err(ErrorResponse { error: format!("Log database error: {}", e) })),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AdminQuery {
+    admin_token: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct RecentError {
+    time: String,
+    error: String,
+}
+
+#[derive(Serialize)]
+pub struct TokenState {
+    id: i64,
+    token_type: String,
+    token: String,                      // Masked key
+    triggered_on: Option<i64>,          // Unix time of last use
+    delay_by_second: i64,               // Current effective cooldown, including any trouble penalty
+    trouble_delay: bool,
+    available: bool,
+    seconds_until_available: i64,       // 0 when available now
+    recent_error: Option<RecentError>,  // Latest failure in LOGS within the last 24 hours
+}
+
+#[derive(Serialize)]
+pub struct ProviderState {
+    total: usize,
+    available: usize,
+    in_trouble: usize,
+    next_available_at: String,          // RFC 3339, now if a token is available
+    seconds_until_available: i64,
+}
+
+#[derive(Serialize)]
+pub struct TokensResponse {
+    now: String,
+    providers: BTreeMap<String, ProviderState>,
+    tokens: Vec<TokenState>,
+}
+
+fn to_rfc3339(timestamp: i64) -> String {
+    Utc.timestamp_opt(timestamp, 0).single().map(|t| t.to_rfc3339()).unwrap_or_default()
+}
+
+// GET /admin/tokens
+pub async fn handle_get_tokens(
+    State(_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(params): Query<AdminQuery>,
+) -> Json<Result<TokensResponse, ErrorResponse>> {
+    if let Err(e) = check_admin_token(&headers, params.admin_token.as_deref()) {
+        return Json(Err(e));
+    }
+
+    let statuses = match db_client::list_token_statuses() {
+        Ok(statuses) => statuses,
+        Err(e) => return Json(Err(ErrorResponse { error: format!("Database error listing tokens: {}", e) })),
+    };
+    // Errors are a nice-to-have, still show the pool if LOGS can't be read
+    let mut recent_errors = match log_client::open_default().and_then(|client| client.recent_errors_by_token(RECENT_ERROR_HOURS)) {
+        Ok(errors) => errors,
+        Err(e) => {
+            warn!(error = %e, "Failed to read recent errors from LOGS");
+            Default::default()
+        }
+    };
+
+    let now = Utc::now().timestamp();
+    let mut providers: BTreeMap<String, ProviderState> = BTreeMap::new();
+    let tokens: Vec<TokenState> = statuses.into_iter().map(|status| {
+        let seconds_until_available = status.seconds_until_available;
+
+        let provider = providers.entry(status.token_type.clone()).or_insert(ProviderState {
+            total: 0,
+            available: 0,
+            in_trouble: 0,
+            next_available_at: String::new(),
+            seconds_until_available: i64::MAX,
+        });
+        provider.total += 1;
+        if seconds_until_available == 0 {
+            provider.available += 1;
+        }
+        if status.trouble_delay {
+            provider.in_trouble += 1;
+        }
+        provider.seconds_until_available = provider.seconds_until_available.min(seconds_until_available);
+
+        TokenState {
+            id: status.id,
+            token_type: status.token_type,
+            token: status.token,
+            triggered_on: status.triggered_on,
+            delay_by_second: status.delay_by_second,
+            trouble_delay: status.trouble_delay,
+            available: seconds_until_available == 0,
+            seconds_until_available,
+            recent_error: recent_errors.remove(&status.id).map(|(time, error)| RecentError { time, error }),
+        }
+    }).collect();
+
+    for provider in providers.values_mut() {
+        provider.next_available_at = to_rfc3339(now + provider.seconds_until_available);
+    }
+
+    Json(Ok(TokensResponse { now: to_rfc3339(now), providers, tokens }))
+}
+
+#[derive(Serialize)]
+pub struct ProviderInfo {
+    token_type: &'static str,
+    capabilities: providers::Capabilities,
+}
+
+#[derive(Serialize)]
+pub struct ProvidersResponse {
+    providers: Vec<ProviderInfo>,
+}
+
+// GET /admin/providers
+pub async fn handle_get_providers(
+    State(_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(params): Query<AdminQuery>,
+) -> Json<Result<ProvidersResponse, ErrorResponse>> {
+    if let Err(e) = check_admin_token(&headers, params.admin_token.as_deref()) {
+        return Json(Err(e));
+    }
+    let providers = providers::all()
+        .iter()
+        .map(|provider| ProviderInfo { token_type: provider.token_type, capabilities: provider.capabilities })
+        .collect();
+    Json(Ok(ProvidersResponse { providers }))
+}
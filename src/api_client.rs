@@ -1,9 +1,17 @@
+use crate::config;
 use crate::db_client;
+use crate::generation::{self, GenerationParams};
 use crate::log_client;
+use crate::metrics;
+use crate::providers;
+use crate::token_queue;
+use regex::Regex;
 use serde_json::{json, Value};
 use std::fmt;
-use std::time::Duration;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
 use tokio::time::sleep;
+use tracing::{error, info, warn};
 
 // Custom error type that implements Send + Sync
 #[derive(Debug)]
@@ -15,165 +23,240 @@ impl fmt::Display for LLMError {
     }
 }
 
+impl LLMError {
+    // Returns a copy of this error with the given secrets and anything that looks like a credential scrubbed out
+    pub fn redacted(self, secrets: &[&str]) -> Self {
+        LLMError(redact(&self.0, secrets))
+    }
+}
+
+const REDACTED: &str = "[REDACTED]";
+
+// Patterns for credentials that may end up in upstream error messages even when we don't know the exact value.
+// Group 1 of every pattern is the prefix to keep, only the rest of the match is replaced.
+fn secret_patterns() -> &'static [Regex] {
+    static PATTERNS: OnceLock<Vec<Regex>> = OnceLock::new();
+    PATTERNS.get_or_init(|| {
+        vec![
+            Regex::new(r"(?i)([?&](?:api_?)?key=)[^&\s\)]+").unwrap(), // Query string keys, e.g. ?key=...
+            Regex::new(r"(?i)(bearer\s+)[A-Za-z0-9._~+/=\-]+").unwrap(), // Authorization headers
+            Regex::new(r"()AIza[0-9A-Za-z_\-]{30,}").unwrap(), // Google API keys
+            Regex::new(r"()sk-[A-Za-z0-9_\-]{16,}").unwrap(), // OpenAI/OpenRouter style keys
+        ]
+    })
+}
+
+/// Scrub secrets out of a message before it leaves the client module (error responses, LOGS rows, stdout).
+/// Exact `secrets` are replaced first, then anything matching a known credential pattern.
+pub fn redact(message: &str, secrets: &[&str]) -> String {
+    let mut redacted = message.to_string();
+    for secret in secrets.iter().filter(|s| !s.is_empty()) {
+        redacted = redacted.replace(secret, REDACTED);
+    }
+    for pattern in secret_patterns() {
+        redacted = pattern.replace_all(&redacted, format!("${{1}}{}", REDACTED)).into_owned();
+    }
+    redacted
+}
+
 // Configuration constants
-pub const MAX_RETRY_ATTEMPTS: u32 = 1;
-pub const RETRY_DELAY_SECONDS: u64 = 30;
+const MAX_OVERLOAD_RETRIES: u32 = 2; // Retries on the same token while the provider reports it is overloaded
+
+/// A successful answer and the token that produced it.
+pub struct Answer {
+    pub content: String,
+    pub json: Option<Value>, // The parsed content, when a response_schema was asked for
+    pub token: db_client::Token,
+}
 
 // Response from API attempt containing both result and used token info
 pub struct AttemptResult {
-    pub result: Result<String, LLMError>
+    pub result: Result<String, LLMError>,
+    // Set when the provider as a whole is overloaded, not this token: retry the same token after this long
+    pub overloaded_retry_after: Option<Duration>,
+    // Set when the provider doesn't know the requested model, which says nothing about the key
+    pub unknown_model: bool,
 }
 
-#[async_trait::async_trait]
-pub trait LLMClient {
-    async fn generate_response(
-        &self,
-        prompt: &str,
-        system_prompt: &str,
-        initial_token_id: i64,
-        log_db: &log_client::DbClient, // Add log client
-        llm_conditions: Option<&[&str]>, // Add LLM conditions for retry
-    ) -> Result<String, LLMError>;
+/// True when an error response says the requested model doesn't exist, as a 404 mentioning the model
+/// (Gemini, Anthropic, OpenAI, Ollama) or a 400 rejecting the model ID (OpenRouter).
+pub fn is_unknown_model(status: reqwest::StatusCode, body: &str) -> bool {
+    let body = body.to_ascii_lowercase();
+    match status.as_u16() {
+        404 => body.contains("model"),
+        400 => ["not a valid model", "model not found", "invalid model", "unknown model"].iter().any(|phrase| body.contains(phrase)),
+        _ => false,
+    }
 }
 
-#[derive(Clone)]
-pub struct OpenRouterClient {
-    api_key: String,
-    model: String,
+/// One provider's client for a single token, built by the factory in its providers:: registry entry.
+#[async_trait::async_trait]
+pub trait LLMClient: Send + Sync {
+    /// The model this client asks for, which picks its generation parameter defaults.
+    fn model(&self) -> &str;
+
+    /// A single call to the provider, without retries. Errors must already have the key redacted.
+    async fn attempt_generate(&self, prompt: &str, system_prompt: &str, params: &GenerationParams) -> AttemptResult;
 }
 
-impl OpenRouterClient {
-    pub fn new(api_key: String, model: String) -> Self {
-        Self { api_key, model }
+// One call to an OpenAI-style /chat/completions endpoint, shared by OpenRouter and openai_compat tokens.
+// An empty api_key sends no Authorization header, for local servers that don't check one.
+pub async fn chat_completion(
+    provider: &str,
+    api_url: &str,
+    api_key: &str,
+    model: &str,
+    prompt: &str,
+    system_prompt: &str,
+    params: &GenerationParams,
+) -> AttemptResult {
+    let mut request_body = json!({
+        "model": model,
+        "messages": [
+            {
+                "role": "system",
+                "content": system_prompt
+            },
+            {
+                "role": "user",
+                "content": prompt
+            }
+        ]
+    });
+    if let Some(temperature) = params.temperature {
+        request_body["temperature"] = json!(temperature);
     }
-
-    async fn attempt_generate(&self, prompt: &str, system_prompt: &str) -> AttemptResult {
-        let request_body = json!({
-            "model": self.model,
-            "messages": [
-                {
-                    "role": "system",
-                    "content": system_prompt
-                },
-                {
-                    "role": "user",
-                    "content": prompt
-                }
-            ]
+    if let Some(max_tokens) = params.max_tokens {
+        request_body["max_tokens"] = json!(max_tokens);
+    }
+    if let Some(top_p) = params.top_p {
+        request_body["top_p"] = json!(top_p);
+    }
+    if let Some(stop) = &params.stop {
+        request_body["stop"] = json!(stop);
+    }
+    if let Some(schema) = &params.response_schema {
+        request_body["response_format"] = json!({
+            "type": "json_schema",
+            "json_schema": { "name": "response", "schema": schema }
         });
+    }
 
-        let api_url = "https://openrouter.ai/api/v1/chat/completions";
-        let client = reqwest::Client::new();
-        
-        let result = async {
-            let response = client
-                .post(api_url)
-                .header("Content-Type", "application/json")
-                .header("Authorization", format!("Bearer {}", self.api_key))
-                .json(&request_body)
-                .send()
-                .await
-                .map_err(|e| LLMError(e.to_string()))?;
+    let client = reqwest::Client::new();
+    let started = Instant::now();
+
+    let mut unknown_model = false;
+    let result = async {
+        let mut request = client
+            .post(api_url)
+            .header("Content-Type", "application/json")
+            .json(&request_body);
+        if !api_key.is_empty() {
+            request = request.header("Authorization", format!("Bearer {}", api_key));
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|e| LLMError(e.without_url().to_string()))?;
 
-            if response.status().is_success() {
-                let response_json: Value = response.json()
-                    .await
-                    .map_err(|e| LLMError(e.to_string()))?;
-                if let Some(choices) = response_json.get("choices") {
-                    if let Some(choice) = choices.get(0) {
-                        if let Some(message) = choice.get("message") {
-                            if let Some(content) = message.get("content") {
-                                if let Some(text) = content.as_str() {
-                                    return Ok(text.to_string());
-                                }
+        if response.status().is_success() {
+            let response_json: Value = response.json()
+                .await
+                .map_err(|e| LLMError(e.without_url().to_string()))?;
+            if let Some(choices) = response_json.get("choices") {
+                if let Some(choice) = choices.get(0) {
+                    if let Some(message) = choice.get("message") {
+                        if let Some(content) = message.get("content") {
+                            if let Some(text) = content.as_str() {
+                                return Ok(text.to_string());
                             }
                         }
                     }
                 }
-                Err(LLMError("Failed to parse OpenRouter response".to_string()))
-            } else {
-                let status = response.status();
-                let error_text = response.text().await.unwrap_or_else(|e| e.to_string());
-                Err(LLMError(format!("Error: {} - {}", status, error_text)))
             }
-        }.await;
-        
-        AttemptResult { result }
+            Err(LLMError(format!("Failed to parse {} response", provider)))
+        } else {
+            let status = response.status();
+            let error_text = response.text().await.unwrap_or_else(|e| e.to_string());
+            unknown_model = is_unknown_model(status, &error_text);
+            Err(LLMError(format!("Error: {} - {}", status, error_text)))
+        }
+    }.await;
+    metrics::record_provider_request(provider, result.is_ok(), started.elapsed().as_secs_f64());
+
+    AttemptResult { result: result.map_err(|e| e.redacted(&[api_key])), overloaded_retry_after: None, unknown_model }
+}
+
+// Mark a token that failed as in trouble, which lengthens its cooldown until it succeeds again
+fn put_in_trouble(token_id: i64) {
+    // Check if token is already marked as "in trouble"
+    match db_client::is_token_in_trouble(token_id) {
+        Ok(true) => {
+            // Already troubled, so clear it first
+            if let Err(clear_err) = db_client::clear_token_trouble(token_id) {
+                warn!(token_id, error = %clear_err, "Failed to clear trouble status");
+            }
+        },
+        Ok(false) => {
+            // Not troubled, continue to mark as troubled
+        },
+        Err(check_err) => {
+            warn!(token_id, error = %check_err, "Failed to check trouble status");
+        }
+    }
+
+    // Then mark as troubled in all cases
+    if let Err(db_err) = db_client::mark_token_trouble(token_id) {
+        warn!(token_id, error = %db_err, "Failed to mark token as troubled");
     }
 }
 
 // Helper function to handle the retry logic
 async fn handle_retry(
     attempts: &mut u32,
-    current_token_id: i64,
-    current_token_type: &str, // Needed for logging
-    current_token_value: &str, // Needed for logging
+    current_token: &db_client::Token, // Needed for logging
     prompt: &str,
     system_prompt: &str,
     error: &LLMError,
     log_db: &log_client::DbClient,
-    llm_conditions: Option<&[&str]>,
-) -> Result<Option<(i64, String, String)>, LLMError> { // Returns Option<(id, value, type)> or fatal Error
+    token_query: db_client::TokenQuery<'_>,
+) -> Result<Option<db_client::Token>, LLMError> { // Returns the next token to use, or a fatal Error
+    let current_token_id = current_token.id;
     *attempts += 1;
-    sleep(Duration::from_secs(RETRY_DELAY_SECONDS)).await; // Sleep before retry
+    metrics::record_retry(&current_token.token_type);
+    let config = config::get();
+    sleep(Duration::from_secs(config.retry_delay_seconds)).await; // Sleep before retry
 
     if let Err(log_err) = log_db.insert_log(
         system_prompt,
         prompt,
-        &error.to_string(),
-        current_token_value,
-        current_token_type,
+        &redact(&error.to_string(), &[&current_token.token]),
+        current_token,
+        false,
     ) {
-        // Use eprintln for errors and make the message more prominent
-        eprintln!("CRITICAL WARNING: FAILED TO LOG ERROR TO DATABASE (data.db): {}", log_err);
-    }
-
-    // Check if token is already marked as "in trouble"
-    match db_client::is_token_in_trouble(current_token_id) {
-        Ok(true) => {
-            // Already troubled, so clear it first
-            if let Err(clear_err) = db_client::clear_token_trouble(current_token_id) {
-                println!(
-                    "Warning: Failed to clear trouble status for token {}: {}", 
-                    current_token_id, clear_err
-                );
-            }
-        },
-        Ok(false) => {
-            // Not troubled, continue to mark as troubled
-        },
-        Err(check_err) => {
-            println!(
-                "Warning: Failed to check trouble status for token {}: {}", 
-                current_token_id, check_err
-            );
-        }
-    }
-
-    // Then mark as troubled in all cases
-    if let Err(db_err) = db_client::mark_token_trouble(current_token_id) {
-        println!("Warning: Failed to mark token {} as troubled: {}", current_token_id, db_err);
+        error!(error = %log_err, "Failed to log error to database");
     }
 
-    if *attempts >= MAX_RETRY_ATTEMPTS {
+    if *attempts >= config.max_retry_attempts {
         return Err(LLMError(format!(
             "Max retry attempts ({}) reached. Last error on token {}: {}",
-            MAX_RETRY_ATTEMPTS, current_token_id, error
+            config.max_retry_attempts, current_token_id, error
         )));
     }
 
-    match db_client::get_next_token_by_llms(llm_conditions) {
+    match token_queue::next_token(token_query).await {
         Ok(Some(new_token)) => {
-            println!(
-                "Attempt {} failed for token {}: {}. Using new token {} ({}) for retry in {} seconds...",
-                *attempts, current_token_id, error, new_token.id, new_token.token_type, RETRY_DELAY_SECONDS
+            warn!(
+                attempt = *attempts, token_id = current_token_id, error = %error,
+                new_token_id = new_token.id, new_token_type = %new_token.token_type,
+                "Attempt failed, retrying with a new token"
             );
-            Ok(Some((new_token.id, new_token.token, new_token.token_type)))
+            Ok(Some(new_token))
         }
         Ok(None) => {
-             println!(
-                "Attempt {} failed for token {}: {}. No other suitable tokens found this time. Retrying after delay...",
-                *attempts, current_token_id, error
+             warn!(
+                attempt = *attempts, token_id = current_token_id, error = %error,
+                "Attempt failed and no other suitable token became available"
             );
              Ok(None)
         }
@@ -186,269 +269,97 @@ async fn handle_retry(
     }
 }
 
-#[async_trait::async_trait]
-impl LLMClient for OpenRouterClient {
-    async fn generate_response(
-        &self,
-        prompt: &str,
-        system_prompt: &str,
-        initial_token_id: i64,
-        log_db: &log_client::DbClient,
-        llm_conditions: Option<&[&str]>,
-    ) -> Result<String, LLMError> {
-        let mut attempts = 0;
-        let mut current_token_id = initial_token_id;
-
-        let initial_token_details = db_client::get_token_by_id(current_token_id)
-            .map_err(|e| LLMError(e.to_string()))?
-            .ok_or_else(|| LLMError(format!("Initial token ID {} not found", current_token_id)))?;
-
-        if initial_token_details.token_type != "openrouter" {
-            return Err(LLMError(format!(
-                "Initial token {} is type '{}', expected 'openrouter'",
-                current_token_id, initial_token_details.token_type
-            )));
-        }
-
-        let mut current_client = OpenRouterClient::new(initial_token_details.token.clone(), self.model.clone());
-        let mut current_token_type = initial_token_details.token_type.clone();
-        let mut current_token_value = initial_token_details.token.clone();
-
-        loop {
-            let attempt_result = current_client.attempt_generate(prompt, system_prompt).await;
-
-            match attempt_result.result {
-                Ok(response) => {
-                    if let Err(log_err) = log_db.insert_log(
-                        system_prompt, prompt, &response, &current_token_value, &current_token_type,
-                    ) {
-                        println!("Warning: Failed to log success: {}", log_err);
-                    }
-                    if let Err(e) = db_client::clear_token_trouble(current_token_id) {
-                        println!("Warning: Failed to clear token trouble status for {}: {}", current_token_id, e);
-                    }
-                    return Ok(response);
-                }
-                Err(e) => {
-                    match handle_retry(
-                        &mut attempts, current_token_id, &current_token_type, &current_token_value,
-                        prompt, system_prompt, &e, log_db, llm_conditions,
-                    ).await {
-                        Ok(Some((new_id, new_token, new_type))) => {
-                            current_token_id = new_id;
-                            current_token_value = new_token.clone();
-                            current_token_type = new_type.clone();
-
-                            if current_token_type == "openrouter" {
-                                current_client = OpenRouterClient::new(current_token_value.clone(), self.model.clone());
-                                println!("Retrying with new OpenRouter token ID: {}", current_token_id);
-                            } else {
-                                println!(
-                                    "Token type changed from 'openrouter' to '{}' (ID: {}). Cannot continue with OpenRouterClient.",
-                                    current_token_type, current_token_id
-                                );
-                                return Err(LLMError(format!(
-                                    "Token type switched to '{}' (ID: {}), requires different client. Last error: {}",
-                                    current_token_type, current_token_id, e
-                                )));
-                            }
-                        }
-                        Ok(None) => {
-                            println!("No suitable token found, sleeping before retry...");
-                            sleep(Duration::from_secs(RETRY_DELAY_SECONDS)).await;
-                            continue;
-                        }
-                        Err(retry_err) => return Err(retry_err),
-                    }
+/// Answer with `initial_token`, moving on to other tokens from `token_query` after errors. Tokens of any
+/// registered type may take over, so the answer comes back with the token that produced it. With a
+/// response_schema, an answer that doesn't match it counts as an error too.
+pub async fn generate_response(
+    prompt: &str,
+    system_prompt: &str,
+    models: &providers::ModelOverrides, // The request's models, which win over each provider's default
+    params: &GenerationParams, // The request's generation parameters, before model defaults
+    initial_token: db_client::Token,
+    log_db: &log_client::DbClient,
+    token_query: db_client::TokenQuery<'_>,
+) -> Result<Answer, LLMError> {
+    let mut attempts = 0;
+    let mut overload_retries = 0;
+    let mut current_token = initial_token;
+
+    loop {
+        let client = match providers::client_for(&current_token, models) {
+            Ok(client) => client,
+            Err(e) => {
+                // An unknown type or a misconfigured row, e.g. without base_url
+                warn!(token_id = current_token.id, token_type = %current_token.token_type, error = %e, "Cannot use token");
+                if let Err(log_err) = log_db.insert_log(system_prompt, prompt, &e.0, &current_token, false) {
+                    error!(error = %log_err, "Failed to log error");
                 }
+                return Err(e);
             }
-        }
-    }
-}
-
-#[derive(Clone)]
-pub struct GeminiClient {
-    api_key: String,
-}
-
-impl GeminiClient {
-    pub fn new(api_key: String) -> Self {
-        Self { api_key }
-    }
-
-    async fn attempt_generate(&self, prompt: &str, system_prompt: &str) -> AttemptResult {
-        let model_id = "gemini-2.5-flash-preview-04-17"; // Corrected model ID if needed, or keep as 2.0
-        let generate_content_api = "streamGenerateContent"; // Use generateContent for non-streaming
-
-        let request_body = json!({
-            "contents": [
-                {
-                    "role": "user",
-                    "parts": [ { "text": prompt } ]
+        };
+        info!(token_id = current_token.id, token_type = %current_token.token_type, "Calling provider");
+        let model_params = params.or(generation::defaults_for(client.model()));
+        let attempt_result = client.attempt_generate(prompt, system_prompt, &model_params).await;
+
+        // Overload is the provider's problem, even once the retries on this key run out, and an unknown model
+        // is the request's
+        let mut blame_token = attempt_result.overloaded_retry_after.is_none() && !attempt_result.unknown_model;
+        // The key answered, but the answer has to match the schema before it is accepted
+        let checked = attempt_result.result.and_then(|response| match &params.response_schema {
+            None => Ok((response, None)),
+            Some(schema) => match generation::parse_answer(&response, schema) {
+                Ok(json) => Ok((response, Some(json))),
+                Err(e) => {
+                    blame_token = false;
+                    Err(LLMError(format!("Answer does not match response_schema: {}", e)))
                 }
-            ],
-            "systemInstruction": {
-                "parts": [ { "text": system_prompt } ]
             },
-            "generationConfig": {
-                "responseMimeType": "text/plain"
-            }
         });
 
-        let api_url = format!(
-            "https://generativelanguage.googleapis.com/v1beta/models/{}:{}?key={}",
-            model_id, generate_content_api, self.api_key
-        );
-
-        let client = reqwest::Client::new();
-        
-        let result = async {
-            let response = client
-                .post(&api_url)
-                .header("Content-Type", "application/json")
-                .json(&request_body)
-                .send()
-                .await
-                .map_err(|e| LLMError(e.to_string()))?;
-
-            if response.status().is_success() {
-                let response_json: Value = response.json()
-                    .await
-                    .map_err(|e| LLMError(format!("Failed to parse JSON response: {}", e)))?;
-                // println!("Debug Gemini response: {:?}", response_json); // Debugging line
-
-                // Handle both array and object root responses
-                // Try array root
-                if let Some(array) = response_json.as_array() {
-                    let mut all_text = String::new();
-                    for obj in array {
-                        if let Some(candidates) = obj.get("candidates") {
-                            for candidate in candidates.as_array().unwrap_or(&vec![]) {
-                                if let Some(content) = candidate.get("content") {
-                                    if let Some(parts) = content.get("parts") {
-                                        for part in parts.as_array().unwrap_or(&vec![]) {
-                                            if let Some(text) = part.get("text") {
-                                                if let Some(text_str) = text.as_str() {
-                                                    all_text.push_str(text_str);
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    if !all_text.is_empty() {
-                        return Ok(all_text);
-                    }
+        match checked {
+            Ok((response, json)) => {
+                if let Err(log_err) = log_db.insert_log(
+                    system_prompt, prompt, &response, &current_token, true,
+                ) {
+                    warn!(error = %log_err, "Failed to log success");
                 }
-                // Try object root (original logic)
-                if let Some(candidates) = response_json.get("candidates") {
-                    let mut all_text = String::new();
-                    for candidate in candidates.as_array().unwrap_or(&vec![]) {
-                        if let Some(content) = candidate.get("content") {
-                            if let Some(parts) = content.get("parts") {
-                                for part in parts.as_array().unwrap_or(&vec![]) {
-                                    if let Some(text) = part.get("text") {
-                                        if let Some(text_str) = text.as_str() {
-                                            all_text.push_str(text_str);
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    if !all_text.is_empty() {
-                        return Ok(all_text);
-                    }
+                if let Err(e) = db_client::clear_token_trouble(current_token.id) {
+                    warn!(token_id = current_token.id, error = %e, "Failed to clear token trouble status");
                 }
-                Err(LLMError(format!("Failed to extract text from Gemini response: {:?}", response_json)))
-            } else {
-                let status = response.status();
-                let error_text = response.text().await.unwrap_or_else(|e| e.to_string());
-                Err(LLMError(format!("Error: {} - {}", status, error_text)))
+                return Ok(Answer { content: response, json, token: current_token });
             }
-        }.await;
-
-        AttemptResult { result }
-    }
-}
-
-#[async_trait::async_trait]
-impl LLMClient for GeminiClient {
-    async fn generate_response(
-        &self,
-        prompt: &str,
-        system_prompt: &str,
-        initial_token_id: i64,
-        log_db: &log_client::DbClient,
-        llm_conditions: Option<&[&str]>,
-    ) -> Result<String, LLMError> {
-        let mut attempts = 0;
-        let mut current_token_id = initial_token_id;
-
-        let initial_token_details = db_client::get_token_by_id(current_token_id)
-            .map_err(|e| LLMError(e.to_string()))?
-            .ok_or_else(|| LLMError(format!("Initial token ID {} not found", current_token_id)))?;
-
-        if initial_token_details.token_type != "gemini" {
-             return Err(LLMError(format!(
-                "Initial token {} is type '{}', expected 'gemini'",
-                current_token_id, initial_token_details.token_type
-            )));
-        }
-
-        let mut current_client = GeminiClient::new(initial_token_details.token.clone());
-        let mut current_token_type = initial_token_details.token_type.clone();
-        let mut current_token_value = initial_token_details.token.clone();
-
-        loop {
-            let attempt_result = current_client.attempt_generate(prompt, system_prompt).await;
-            // println!("Debug Gemini attempt result: {:?}", attempt_result.result);
-
-            match attempt_result.result {
-                Ok(response) => {
-                    if let Err(log_err) = log_db.insert_log(
-                        system_prompt, prompt, &response, &current_token_value, &current_token_type,
-                    ) {
-                        println!("Warning: Failed to log success: {}", log_err);
-                    }
-                    if let Err(e) = db_client::clear_token_trouble(current_token_id) {
-                        println!("Warning: Failed to clear token trouble status for {}: {}", current_token_id, e);
+            Err(e) => {
+                // Overload (e.g. Anthropic's 529) is not the key's fault: wait and try the same key again without marking it
+                if let (Some(delay), true) = (attempt_result.overloaded_retry_after, overload_retries < MAX_OVERLOAD_RETRIES) {
+                    overload_retries += 1;
+                    warn!(token_id = current_token.id, retry_in_seconds = delay.as_secs(), "Provider is overloaded, retrying the same token");
+                    if let Err(log_err) = log_db.insert_log(system_prompt, prompt, &e.to_string(), &current_token, false) {
+                        error!(error = %log_err, "Failed to log error to database");
                     }
-                    return Ok(response);
+                    sleep(delay).await;
+                    continue;
                 }
-                Err(e) => {
-                    match handle_retry(
-                        &mut attempts, current_token_id, &current_token_type, &current_token_value,
-                        prompt, system_prompt, &e, log_db, llm_conditions,
-                    ).await {
-                        Ok(Some((new_id, new_token, new_type))) => {
-                            current_token_id = new_id;
-                            current_token_value = new_token.clone();
-                            current_token_type = new_type.clone();
-
-                            if current_token_type == "gemini" {
-                                current_client = GeminiClient::new(current_token_value.clone());
-                                println!("Retrying with new Gemini token ID: {}", current_token_id);
-                            } else {
-                                println!(
-                                    "Token type changed from 'gemini' to '{}' (ID: {}). Cannot continue with GeminiClient.",
-                                    current_token_type, current_token_id
-                                );
-                                return Err(LLMError(format!(
-                                    "Token type switched to '{}' (ID: {}), requires different client. Last error: {}",
-                                    current_token_type, current_token_id, e
-                                )));
-                            }
-                        }
-                        Ok(None) => {
-                            println!("No suitable token found, sleeping before retry...");
-                            sleep(Duration::from_secs(RETRY_DELAY_SECONDS)).await;
-                            continue;
-                        }
-                        Err(retry_err) => return Err(retry_err),
+                // A key that answered with unusable output, was turned away by an overloaded provider or was
+                // asked for a model its provider doesn't have isn't at fault
+                if blame_token {
+                    put_in_trouble(current_token.id);
+                }
+                match handle_retry(
+                    &mut attempts, &current_token,
+                    prompt, system_prompt, &e, log_db, token_query,
+                ).await {
+                    Ok(Some(new_token)) => {
+                        info!(token_id = new_token.id, token_type = %new_token.token_type, "Retrying with new token");
+                        current_token = new_token;
+                        overload_retries = 0;
+                    }
+                    Ok(None) => {
+                        return Err(LLMError(format!(
+                            "No token became available to retry after error on token {}: {}",
+                            current_token.id, e
+                        )));
                     }
+                    Err(retry_err) => return Err(retry_err),
                 }
             }
         }
@@ -0,0 +1,275 @@
+use axum::{
+    extract::{Json, State},
+    http::HeaderMap,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tracing::{info, info_span, warn, Instrument};
+
+use crate::{
+    check_access_token, db_client, handle_chat_request, request_id_from, AppState, ChatRequest, ChatResponse,
+    ErrorResponse, REQUEST_ID_HEADER,
+};
+
+// Batches: many prompts run in parallel, one worker per matching token so every key in the pool
+// is kept busy. A worker that finds the pool exhausted waits in the token queue like any request,
+// and keeps waiting past SAFE_TRIGGER_QUEUE_TIMEOUT_SECONDS instead of failing the item.
+
+const MAX_BATCH_ITEMS: usize = 500;
+const MAX_CONCURRENCY: usize = 32;
+// Re-check the pool at least this often while waiting, other processes may free or add tokens
+const MAX_WAIT_SECONDS: u64 = 30;
+
+pub type ItemResult = Result<ChatResponse, ErrorResponse>;
+
+// Set when the pool has no free token for the request's LLMs right now, see call_providers
+fn pool_exhausted(result: &ItemResult) -> bool {
+    matches!(result, Err(e) if e.error.starts_with("No available tokens"))
+}
+
+// Run one item, waiting out key cooldowns for as long as some matching token exists
+async fn run_item(state: Arc<AppState>, request: ChatRequest, request_id: &str) -> ItemResult {
+    let llms: Option<Vec<String>> = request
+        .llm
+        .as_ref()
+        .map(|s| s.split(',').map(|x| x.trim().to_string()).filter(|x| !x.is_empty()).collect());
+    loop {
+        let Json(result) = handle_chat_request(state.clone(), request.clone(), request_id).await;
+        if !pool_exhausted(&result) {
+            return result;
+        }
+        let llm_refs: Option<Vec<&str>> = llms.as_ref().map(|l| l.iter().map(String::as_str).collect());
+        match db_client::token_availability(llm_refs.as_deref()) {
+            Ok((count, Some(wait))) if count > 0 => {
+                tokio::time::sleep(Duration::from_secs((wait as u64).clamp(1, MAX_WAIT_SECONDS))).await;
+            }
+            Ok(_) => return result, // No token matches at all, waiting won't help
+            Err(e) => return Err(ErrorResponse { error: format!("Database error checking token availability: {}", e) }),
+        }
+    }
+}
+
+/// Workers to use for a batch over `llms`: one per matching token, within sane bounds.
+pub fn concurrency_for(llms: Option<&[&str]>) -> usize {
+    match db_client::token_availability(llms) {
+        Ok((count, _)) => (count.max(1) as usize).min(MAX_CONCURRENCY),
+        Err(e) => {
+            warn!(error = %e, "Failed to count tokens for batch, running items one at a time");
+            1
+        }
+    }
+}
+
+/// Run `items` with `concurrency` workers, sending each (index, result) as soon as it is done.
+/// Items are started in order; item `i` is logged with request ID "{request_id}-{i}".
+pub async fn run_batch(
+    state: Arc<AppState>,
+    items: Vec<(usize, ChatRequest)>,
+    request_id: &str,
+    concurrency: usize,
+    results: mpsc::UnboundedSender<(usize, ItemResult)>,
+) {
+    let queue = Arc::new(Mutex::new(VecDeque::from(items)));
+    let mut workers = JoinSet::new();
+    for _ in 0..concurrency.max(1) {
+        let (state, queue, results, request_id) = (state.clone(), queue.clone(), results.clone(), request_id.to_string());
+        workers.spawn(
+            async move {
+                loop {
+                    let Some((index, request)) = queue.lock().unwrap().pop_front() else { break };
+                    let item_request_id = format!("{}-{}", request_id, index);
+                    let span = info_span!("batch_item", index, request_id = %item_request_id);
+                    let result = run_item(state.clone(), request, &item_request_id).instrument(span).await;
+                    if results.send((index, result)).is_err() {
+                        break; // Nobody is collecting results any more
+                    }
+                }
+            }
+            .in_current_span(),
+        );
+    }
+    while let Some(joined) = workers.join_next().await {
+        if let Err(e) = joined {
+            warn!(error = %e, "Batch worker panicked");
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct BatchRequest {
+    prompts: Vec<String>,
+    #[serde(flatten)]
+    chat: ChatRequest, // Every other field, shared by all prompts
+}
+
+#[derive(Serialize)]
+pub struct BatchResponse {
+    results: Vec<ItemResult>, // Same order as the prompts, each like a /api/chat response
+}
+
+// POST /api/batch
+pub async fn handle_batch(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(batch): Json<BatchRequest>,
+) -> ([(&'static str, String); 1], Json<Result<BatchResponse, ErrorResponse>>) {
+    let request_id = request_id_from(&headers);
+    let response_headers = [(REQUEST_ID_HEADER, request_id.clone())];
+    if let Err(e) = check_access_token(batch.chat.access_token.as_deref()) {
+        return (response_headers, Json(Err(e)));
+    }
+    if batch.prompts.is_empty() || batch.prompts.len() > MAX_BATCH_ITEMS {
+        return (response_headers, Json(Err(ErrorResponse {
+            error: format!("A batch needs between 1 and {} prompts, got {}", MAX_BATCH_ITEMS, batch.prompts.len()),
+        })));
+    }
+
+    let span = info_span!("batch", request_id = %request_id, items = batch.prompts.len());
+    let total = batch.prompts.len();
+    let llms: Option<Vec<&str>> = batch.chat.llm.as_ref().map(|s| s.split(',').map(str::trim).filter(|x| !x.is_empty()).collect());
+    let concurrency = concurrency_for(llms.as_deref()).min(total);
+    let items: Vec<(usize, ChatRequest)> = batch
+        .prompts
+        .iter()
+        .enumerate()
+        .map(|(index, prompt)| {
+            (index, ChatRequest { prompt: prompt.clone(), access_token: None, ..batch.chat.clone() })
+        })
+        .collect();
+
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    async {
+        info!(concurrency, "Running batch");
+        run_batch(state, items, &request_id, concurrency, sender).await;
+    }
+    .instrument(span)
+    .await;
+
+    let mut results: Vec<Option<ItemResult>> = (0..total).map(|_| None).collect();
+    while let Some((index, result)) = receiver.recv().await {
+        results[index] = Some(result);
+    }
+    let results = results
+        .into_iter()
+        .map(|r| r.unwrap_or_else(|| Err(ErrorResponse { error: "Batch item was not processed".to_string() })))
+        .collect();
+    (response_headers, Json(Ok(BatchResponse { results })))
+}
+
+// Offline batches: `safe-trigger batch input.jsonl output.jsonl` runs every line of the input through the
+// same scheduler. Each input line is a /api/chat body, optionally with an "id" that is copied to its output
+// record. The output file doubles as the checkpoint: records are appended as items finish, so a rerun with
+// the same files skips every line that already has a record.
+
+#[derive(Deserialize)]
+struct InputLine {
+    id: Option<Value>,
+    #[serde(flatten)]
+    request: ChatRequest,
+}
+
+// One output record: the input line number, its id, and either the answer fields or "error"
+fn output_record(line: usize, id: Option<&Value>, result: &ItemResult) -> String {
+    let mut record = match result {
+        Ok(response) => serde_json::to_value(response).unwrap_or_default(),
+        Err(e) => serde_json::json!({ "error": e.error }),
+    };
+    if let Some(fields) = record.as_object_mut() {
+        fields.insert("line".to_string(), line.into());
+        if let Some(id) = id {
+            fields.insert("id".to_string(), id.clone());
+        }
+    }
+    record.to_string()
+}
+
+// Line numbers already recorded in the output, after cutting off a record left half-written by a crash
+fn completed_lines(output_path: &str) -> std::io::Result<HashSet<usize>> {
+    let Ok(contents) = fs::read_to_string(output_path) else { return Ok(HashSet::new()) };
+    let complete_len = contents.rfind('\n').map_or(0, |i| i + 1);
+    if complete_len < contents.len() {
+        OpenOptions::new().write(true).open(output_path)?.set_len(complete_len as u64)?;
+    }
+    Ok(contents[..complete_len]
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .filter_map(|record| record.get("line")?.as_u64())
+        .map(|line| line as usize)
+        .collect())
+}
+
+/// Process `input_path` into `output_path`, resuming from whatever the output already holds.
+pub async fn run_file(state: Arc<AppState>, input_path: &str, output_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let done = completed_lines(output_path)?;
+    let mut output = OpenOptions::new().create(true).append(true).open(output_path)?;
+
+    let mut items = Vec::new();
+    let mut ids = HashMap::new();
+    let (mut skipped, mut invalid) = (0, 0);
+    for (index, line) in BufReader::new(File::open(input_path)?).lines().enumerate() {
+        let (number, line) = (index + 1, line?);
+        if line.trim().is_empty() {
+            continue;
+        }
+        if done.contains(&number) {
+            skipped += 1;
+            continue;
+        }
+        match serde_json::from_str::<InputLine>(&line) {
+            Ok(input) => {
+                if let Some(id) = input.id {
+                    ids.insert(number, id);
+                }
+                items.push((number, input.request));
+            }
+            Err(e) => {
+                // Bad lines become error records too, so they are reported once and not retried
+                let id = serde_json::from_str::<Value>(&line).ok().and_then(|v| v.get("id").cloned());
+                let error = Err(ErrorResponse { error: format!("Invalid input line: {}", e) });
+                writeln!(output, "{}", output_record(number, id.as_ref(), &error))?;
+                invalid += 1;
+            }
+        }
+    }
+    output.flush()?;
+
+    let total = items.len();
+    println!(
+        "{} line(s) to process, {} already done, {} invalid. Writing results to {}.",
+        total, skipped, invalid, output_path
+    );
+    if total == 0 {
+        return Ok(());
+    }
+
+    let run_id = uuid::Uuid::new_v4().to_string();
+    let concurrency = concurrency_for(None).min(total);
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let writer = async {
+        let (mut written, mut failed) = (0, 0);
+        while let Some((number, result)) = receiver.recv().await {
+            // Flushed per record so an interrupted run loses at most the items in flight
+            writeln!(output, "{}", output_record(number, ids.get(&number), &result))?;
+            output.flush()?;
+            written += 1;
+            if result.is_err() {
+                failed += 1;
+            }
+            if written % 10 == 0 || written == total {
+                println!("{}/{} done, {} failed", written, total, failed);
+            }
+        }
+        Ok::<_, std::io::Error>(())
+    };
+    let span = info_span!("batch", request_id = %run_id, items = total);
+    let (_, written) = tokio::join!(run_batch(state, items, &run_id, concurrency, sender).instrument(span), writer);
+    written?;
+    Ok(())
+}
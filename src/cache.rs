@@ -0,0 +1,70 @@
+use chrono::Utc;
+use rusqlite::{Connection, OptionalExtension, Result, params};
+use sha2::{Digest, Sha256};
+
+use crate::config;
+use crate::generation::GenerationParams;
+use crate::metrics;
+
+// A cached answer and the token type that originally produced it
+pub struct CachedResponse {
+    pub content: String,
+    pub token_type: String,
+}
+
+/// True if the response cache is turned on (SAFE_TRIGGER_CACHE_TTL_SECONDS > 0).
+pub fn enabled() -> bool {
+    config::get().cache_ttl_seconds.is_some()
+}
+
+/// Cache key for a request: a hash of the exact system prompt, prompt, LLM list, model and generation parameters.
+pub fn key(system_prompt: &str, prompt: &str, llms: Option<&[&str]>, model: Option<&str>, params: &GenerationParams) -> String {
+    // JSON encoding keeps field boundaries unambiguous
+    let mut material = serde_json::json!([system_prompt, prompt, llms.map(|l| l.join(",")), model]);
+    if !params.is_empty() {
+        // Only appended when set, so keys of requests without parameters stay the same
+        material.as_array_mut().unwrap().push(serde_json::json!(params));
+    }
+    format!("{:x}", Sha256::digest(material.to_string().as_bytes()))
+}
+
+fn open() -> Result<Connection> {
+    let conn = Connection::open(&config::get().cache_db_path)?;
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS RESPONSE_CACHE (
+            cache_key TEXT PRIMARY KEY,
+            response TEXT NOT NULL,
+            token_type TEXT NOT NULL,
+            created_at INTEGER NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS idx_response_cache_created_at ON RESPONSE_CACHE (created_at);",
+    )?;
+    Ok(conn)
+}
+
+/// Look up an unexpired cached response.
+pub fn lookup(cache_key: &str) -> Result<Option<CachedResponse>> {
+    let Some(ttl) = config::get().cache_ttl_seconds else { return Ok(None) };
+    let _timer = metrics::db_timer("cache_lookup");
+    let conn = open()?;
+    let cutoff = Utc::now().timestamp() - ttl as i64;
+    conn.query_row(
+        "SELECT response, token_type FROM RESPONSE_CACHE WHERE cache_key = ?1 AND created_at > ?2",
+        params![cache_key, cutoff],
+        |row| Ok(CachedResponse { content: row.get(0)?, token_type: row.get(1)? }),
+    ).optional()
+}
+
+/// Store a fresh response, replacing any previous entry for the key, and drop expired entries.
+pub fn store(cache_key: &str, content: &str, token_type: &str) -> Result<()> {
+    let Some(ttl) = config::get().cache_ttl_seconds else { return Ok(()) };
+    let _timer = metrics::db_timer("cache_store");
+    let conn = open()?;
+    let now = Utc::now().timestamp();
+    conn.execute(
+        "INSERT OR REPLACE INTO RESPONSE_CACHE (cache_key, response, token_type, created_at) VALUES (?1, ?2, ?3, ?4)",
+        params![cache_key, content, token_type, now],
+    )?;
+    conn.execute("DELETE FROM RESPONSE_CACHE WHERE created_at <= ?1", params![now - ttl as i64])?;
+    Ok(())
+}
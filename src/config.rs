@@ -0,0 +1,135 @@
+use std::sync::OnceLock;
+
+use crate::selection;
+
+// Server settings read from SAFE_TRIGGER_* environment variables. Every setting has a default
+// so a bare `./safe-trigger` next to a data.db keeps working as before.
+pub struct Config {
+    pub db_path: String,                           // SAFE_TRIGGER_DB: token pool database
+    pub log_db_path: String,                       // SAFE_TRIGGER_LOG_DB: LOGS database, defaults to the token pool database
+    pub log_retention_days: Option<u64>,           // SAFE_TRIGGER_LOG_RETENTION_DAYS: delete LOGS rows older than this
+    pub log_max_rows: Option<u64>,                 // SAFE_TRIGGER_LOG_MAX_ROWS: keep at most this many LOGS rows
+    pub log_prune_interval_seconds: u64,           // SAFE_TRIGGER_LOG_PRUNE_INTERVAL_SECONDS: how often the pruning task runs
+    pub log_vacuum_interval_hours: Option<u64>,    // SAFE_TRIGGER_LOG_VACUUM_INTERVAL_HOURS: VACUUM the LOGS database this often
+    pub json_logs: bool,                           // SAFE_TRIGGER_LOG_FORMAT=json: diagnostics as JSON lines instead of text
+    pub cache_ttl_seconds: Option<u64>,            // SAFE_TRIGGER_CACHE_TTL_SECONDS: enables the exact-match response cache
+    pub cache_db_path: String,                     // SAFE_TRIGGER_CACHE_DB: response cache database, defaults to the LOGS database
+    pub semantic_cache_threshold: Option<f32>,     // SAFE_TRIGGER_SEMANTIC_CACHE_THRESHOLD: cosine similarity (0-1] that enables the semantic cache
+    pub semantic_cache_db_path: String,            // SAFE_TRIGGER_SEMANTIC_CACHE_DB: vector index database
+    pub semantic_cache_max_entries: u64,           // SAFE_TRIGGER_SEMANTIC_CACHE_MAX_ENTRIES: oldest entries are dropped beyond this
+    pub embedding_model: String,                   // SAFE_TRIGGER_EMBEDDING_MODEL: Gemini embedding model
+    pub jobs_db_path: String,                      // SAFE_TRIGGER_JOBS_DB: async job queue database, defaults to the LOGS database
+    pub job_workers: usize,                        // SAFE_TRIGGER_JOB_WORKERS: jobs executed concurrently
+    pub job_retention_days: u64,                   // SAFE_TRIGGER_JOB_RETENTION_DAYS: finished jobs are deleted after this
+    pub callback_allowed_hosts: Vec<String>,       // SAFE_TRIGGER_CALLBACK_ALLOWED_HOSTS: job callback hosts exempt from the non-public address check
+    pub ollama_concurrency: usize,                 // SAFE_TRIGGER_OLLAMA_CONCURRENCY: requests each Ollama model serves at once
+    pub token_selection: String,                   // SAFE_TRIGGER_TOKEN_SELECTION: default token selection strategy, see selection.rs
+    pub queue_timeout_seconds: u64,                // SAFE_TRIGGER_QUEUE_TIMEOUT_SECONDS: longest wait for a token when the pool is exhausted, 0 fails at once
+    pub providers: Vec<String>,                    // SAFE_TRIGGER_PROVIDERS: token types /readyz requires, defaults to every type in TOKENS
+    pub port: u16,                                 // SAFE_TRIGGER_PORT: HTTP port the server listens on
+    pub max_retry_attempts: u32,                   // SAFE_TRIGGER_MAX_RETRY_ATTEMPTS: tokens tried per request, 1 means no retry on another token
+    pub retry_delay_seconds: u64,                  // SAFE_TRIGGER_RETRY_DELAY_SECONDS: pause after a failed attempt before the next token
+    pub gemini_base_url: String,                   // SAFE_TRIGGER_GEMINI_BASE_URL: Gemini API root, e.g. a proxy or a mock server
+    pub openrouter_base_url: String,               // SAFE_TRIGGER_OPENROUTER_BASE_URL: OpenRouter API root
+    pub anthropic_base_url: String,                // SAFE_TRIGGER_ANTHROPIC_BASE_URL: Anthropic API root
+    pub model_defaults_path: String,               // SAFE_TRIGGER_MODEL_DEFAULTS: JSON file of per-model generation parameter defaults
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let db_path = env_string("SAFE_TRIGGER_DB").unwrap_or_else(|| "data.db".to_string());
+        let log_db_path = env_string("SAFE_TRIGGER_LOG_DB").unwrap_or_else(|| db_path.clone());
+        let cache_db_path = env_string("SAFE_TRIGGER_CACHE_DB").unwrap_or_else(|| log_db_path.clone());
+        let jobs_db_path = env_string("SAFE_TRIGGER_JOBS_DB").unwrap_or_else(|| log_db_path.clone());
+        let semantic_cache_db_path = env_string("SAFE_TRIGGER_SEMANTIC_CACHE_DB")
+            .unwrap_or_else(|| sibling_path(&db_path, "semantic_cache.db"));
+        Self {
+            db_path,
+            log_db_path,
+            cache_ttl_seconds: env_u64("SAFE_TRIGGER_CACHE_TTL_SECONDS").filter(|ttl| *ttl > 0),
+            cache_db_path,
+            semantic_cache_threshold: env_string("SAFE_TRIGGER_SEMANTIC_CACHE_THRESHOLD")
+                .and_then(|value| value.parse::<f32>().ok())
+                .filter(|threshold| *threshold > 0.0 && *threshold <= 1.0),
+            semantic_cache_db_path,
+            jobs_db_path,
+            job_workers: env_u64("SAFE_TRIGGER_JOB_WORKERS").unwrap_or(4) as usize,
+            job_retention_days: env_u64("SAFE_TRIGGER_JOB_RETENTION_DAYS").unwrap_or(7).max(1),
+            callback_allowed_hosts: env_list("SAFE_TRIGGER_CALLBACK_ALLOWED_HOSTS"),
+            semantic_cache_max_entries: env_u64("SAFE_TRIGGER_SEMANTIC_CACHE_MAX_ENTRIES").unwrap_or(10_000).max(1),
+            embedding_model: env_string("SAFE_TRIGGER_EMBEDDING_MODEL").unwrap_or_else(|| "text-embedding-004".to_string()),
+            log_retention_days: env_u64("SAFE_TRIGGER_LOG_RETENTION_DAYS").filter(|days| *days > 0),
+            log_max_rows: env_u64("SAFE_TRIGGER_LOG_MAX_ROWS").filter(|rows| *rows > 0),
+            log_prune_interval_seconds: env_u64("SAFE_TRIGGER_LOG_PRUNE_INTERVAL_SECONDS").unwrap_or(3600).max(1),
+            log_vacuum_interval_hours: env_u64("SAFE_TRIGGER_LOG_VACUUM_INTERVAL_HOURS").filter(|hours| *hours > 0),
+            ollama_concurrency: env_u64("SAFE_TRIGGER_OLLAMA_CONCURRENCY").unwrap_or(1).max(1) as usize,
+            token_selection: env_choice("SAFE_TRIGGER_TOKEN_SELECTION", selection::NAMES).unwrap_or_else(|| "lru".to_string()),
+            queue_timeout_seconds: env_u64("SAFE_TRIGGER_QUEUE_TIMEOUT_SECONDS").unwrap_or(120),
+            providers: env_list("SAFE_TRIGGER_PROVIDERS"),
+            port: env_u64("SAFE_TRIGGER_PORT").and_then(|port| u16::try_from(port).ok()).unwrap_or(3000),
+            max_retry_attempts: env_u64("SAFE_TRIGGER_MAX_RETRY_ATTEMPTS").unwrap_or(1).clamp(1, 100) as u32,
+            retry_delay_seconds: env_u64("SAFE_TRIGGER_RETRY_DELAY_SECONDS").unwrap_or(30),
+            gemini_base_url: env_url("SAFE_TRIGGER_GEMINI_BASE_URL", "https://generativelanguage.googleapis.com/v1beta"),
+            openrouter_base_url: env_url("SAFE_TRIGGER_OPENROUTER_BASE_URL", "https://openrouter.ai/api/v1"),
+            anthropic_base_url: env_url("SAFE_TRIGGER_ANTHROPIC_BASE_URL", "https://api.anthropic.com/v1"),
+            model_defaults_path: env_string("SAFE_TRIGGER_MODEL_DEFAULTS").unwrap_or_else(|| "model_defaults.json".to_string()),
+            json_logs: env_string("SAFE_TRIGGER_LOG_FORMAT").is_some_and(|format| format.eq_ignore_ascii_case("json")),
+        }
+    }
+
+    // True if logs live in their own file rather than next to TOKENS
+    pub fn separate_log_db(&self) -> bool {
+        self.log_db_path != self.db_path
+    }
+}
+
+/// Global configuration, loaded from the environment on first use.
+pub fn get() -> &'static Config {
+    static CONFIG: OnceLock<Config> = OnceLock::new();
+    CONFIG.get_or_init(Config::from_env)
+}
+
+// A file named `file_name` in the same directory as `path`
+fn sibling_path(path: &str, file_name: &str) -> String {
+    std::path::Path::new(path)
+        .with_file_name(file_name)
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn env_string(name: &str) -> Option<String> {
+    std::env::var(name).ok().map(|v| v.trim().to_string()).filter(|v| !v.is_empty())
+}
+
+// A comma-separated list, empty when unset
+fn env_list(name: &str) -> Vec<String> {
+    env_string(name)
+        .map(|list| list.split(',').map(|item| item.trim().to_string()).filter(|item| !item.is_empty()).collect())
+        .unwrap_or_default()
+}
+
+fn env_u64(name: &str) -> Option<u64> {
+    let value = env_string(name)?;
+    match value.parse::<u64>() {
+        Ok(parsed) => Some(parsed),
+        Err(_) => {
+            // Logging isn't set up yet when the config is first read
+            eprintln!("Warning: Ignoring {}={:?}, expected a non-negative integer", name, value);
+            None
+        }
+    }
+}
+
+// An API root without its trailing slash, so paths can be appended with format!("{}/...")
+fn env_url(name: &str, default: &str) -> String {
+    env_string(name).unwrap_or_else(|| default.to_string()).trim_end_matches('/').to_string()
+}
+
+fn env_choice(name: &str, choices: &[&str]) -> Option<String> {
+    let value = env_string(name)?;
+    if choices.contains(&value.as_str()) {
+        return Some(value);
+    }
+    eprintln!("Warning: Ignoring {}={:?}, expected one of: {}", name, value, choices.join(", "));
+    None
+}
@@ -0,0 +1,216 @@
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::fmt;
+use std::fs;
+use std::sync::OnceLock;
+
+// Stored token values starting with this prefix are AES-256-GCM encrypted; anything else is legacy plaintext
+pub const ENCRYPTED_PREFIX: &str = "enc:v1:";
+const NONCE_LEN: usize = 12;
+const KEY_LEN: usize = 32;
+
+// Where the master key comes from, checked in this order
+pub const MASTER_KEY_ENV: &str = "SAFE_TRIGGER_MASTER_KEY";
+pub const MASTER_KEY_FILE_ENV: &str = "SAFE_TRIGGER_MASTER_KEY_FILE";
+pub const DEFAULT_MASTER_KEY_FILE: &str = "master_key.txt";
+
+// Only used by the rekey command, to decrypt values written under the previous master key
+pub const OLD_MASTER_KEY_ENV: &str = "SAFE_TRIGGER_OLD_MASTER_KEY";
+pub const OLD_MASTER_KEY_FILE_ENV: &str = "SAFE_TRIGGER_OLD_MASTER_KEY_FILE";
+
+#[derive(Debug, Clone)]
+pub struct CryptoError(pub String);
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+pub struct MasterKey {
+    cipher: Aes256Gcm,
+}
+
+impl MasterKey {
+    /// A master key given as 32 random bytes in base64, e.g. from `openssl rand -base64 32`. Anything
+    /// weaker could be guessed offline by whoever holds a copy of the database.
+    pub fn from_base64(encoded: &str) -> Result<Self, CryptoError> {
+        let bytes = STANDARD.decode(encoded.trim()).map_err(|_| CryptoError(
+            "The master key must be 32 random bytes in base64, e.g. from `openssl rand -base64 32`".to_string()
+        ))?;
+        if bytes.len() != KEY_LEN {
+            return Err(CryptoError(format!(
+                "The master key must be 32 random bytes in base64, e.g. from `openssl rand -base64 32`, got {} bytes",
+                bytes.len()
+            )));
+        }
+        Ok(Self { cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&bytes)) })
+    }
+
+    pub fn encrypt(&self, plaintext: &str) -> Result<String, CryptoError> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self.cipher
+            .encrypt(&nonce, plaintext.as_bytes())
+            .map_err(|_| CryptoError("Failed to encrypt token".to_string()))?;
+
+        let mut payload = nonce.to_vec();
+        payload.extend_from_slice(&ciphertext);
+        Ok(format!("{}{}", ENCRYPTED_PREFIX, STANDARD.encode(payload)))
+    }
+
+    pub fn decrypt(&self, stored: &str) -> Result<String, CryptoError> {
+        let encoded = stored.strip_prefix(ENCRYPTED_PREFIX)
+            .ok_or_else(|| CryptoError("Token value is not encrypted".to_string()))?;
+        let payload = STANDARD.decode(encoded)
+            .map_err(|e| CryptoError(format!("Encrypted token is not valid base64: {}", e)))?;
+        if payload.len() <= NONCE_LEN {
+            return Err(CryptoError("Encrypted token is truncated".to_string()));
+        }
+
+        let (nonce, ciphertext) = payload.split_at(NONCE_LEN);
+        let plaintext = self.cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| CryptoError("Failed to decrypt token, wrong master key?".to_string()))?;
+        String::from_utf8(plaintext).map_err(|e| CryptoError(format!("Decrypted token is not UTF-8: {}", e)))
+    }
+}
+
+pub fn is_encrypted(stored: &str) -> bool {
+    stored.starts_with(ENCRYPTED_PREFIX)
+}
+
+/// A stored value encrypted under `new_key`, for the rekey command. Plaintext is encrypted; encrypted values
+/// are decrypted with the old keys first, then the new one so an interrupted rotation can simply be re-run.
+pub fn reencrypt(stored: &str, old_keys: &[MasterKey], new_key: &MasterKey) -> Result<String, CryptoError> {
+    let plaintext = if is_encrypted(stored) {
+        old_keys
+            .iter()
+            .map(|key| key.decrypt(stored))
+            .find(|result| result.is_ok())
+            .unwrap_or_else(|| new_key.decrypt(stored))?
+    } else {
+        stored.to_string()
+    };
+    new_key.encrypt(&plaintext)
+}
+
+// Reads a master key from the given env var, or from the file named by the file env var (or the default file)
+fn load_secret(env_name: &str, file_env_name: &str, default_file: Option<&str>) -> Option<String> {
+    if let Ok(secret) = std::env::var(env_name) {
+        if !secret.trim().is_empty() {
+            return Some(secret.trim().to_string());
+        }
+    }
+
+    let path = std::env::var(file_env_name).ok().or_else(|| default_file.map(str::to_string))?;
+    match fs::read_to_string(&path) {
+        Ok(secret) if !secret.trim().is_empty() => Some(secret.trim().to_string()),
+        _ => None, // Treat as not configured if the file is empty or unreadable, like access_token.txt
+    }
+}
+
+fn loaded_master_key() -> &'static Result<Option<MasterKey>, CryptoError> {
+    static MASTER_KEY: OnceLock<Result<Option<MasterKey>, CryptoError>> = OnceLock::new();
+    MASTER_KEY.get_or_init(|| {
+        load_secret(MASTER_KEY_ENV, MASTER_KEY_FILE_ENV, Some(DEFAULT_MASTER_KEY_FILE))
+            .map(|secret| MasterKey::from_base64(&secret))
+            .transpose()
+    })
+}
+
+/// The current master key, loaded once from `SAFE_TRIGGER_MASTER_KEY`, `SAFE_TRIGGER_MASTER_KEY_FILE` or `master_key.txt`.
+/// `None` means encryption at rest is not configured and tokens are stored in plaintext.
+pub fn master_key() -> Option<&'static MasterKey> {
+    loaded_master_key().as_ref().ok().and_then(Option::as_ref)
+}
+
+/// Fail if a master key is configured but unusable. Checked at startup, so a bad key never means plaintext.
+pub fn check_master_key() -> Result<(), CryptoError> {
+    loaded_master_key().as_ref().map(|_| ()).map_err(CryptoError::clone)
+}
+
+/// The previous master key, only needed while rotating keys with the `rekey` command.
+pub fn old_master_key() -> Result<Option<MasterKey>, CryptoError> {
+    load_secret(OLD_MASTER_KEY_ENV, OLD_MASTER_KEY_FILE_ENV, None)
+        .map(|secret| MasterKey::from_base64(&secret))
+        .transpose()
+}
+
+/// Turn a value from `TOKENS.token` into the usable API key. Plaintext values are passed through unchanged.
+pub fn reveal_token(stored: &str) -> Result<String, CryptoError> {
+    if !is_encrypted(stored) {
+        return Ok(stored.to_string());
+    }
+    match master_key() {
+        Some(key) => key.decrypt(stored),
+        None => Err(CryptoError(format!(
+            "Token is encrypted but no master key is configured (set {} or {})",
+            MASTER_KEY_ENV, MASTER_KEY_FILE_ENV
+        ))),
+    }
+}
+
+/// Masked form of an API key that is safe to log or show to operators, e.g. `AIza...9xQk`.
+pub fn mask_token(token: &str) -> String {
+    if is_encrypted(token) {
+        return "[encrypted]".to_string();
+    }
+    let chars: Vec<char> = token.chars().collect();
+    if chars.len() <= 8 {
+        return "*".repeat(chars.len());
+    }
+    let head: String = chars[..4].iter().collect();
+    let tail: String = chars[chars.len() - 4..].iter().collect();
+    format!("{}...{}", head, tail)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn random_key() -> String {
+        STANDARD.encode(rand::random::<[u8; KEY_LEN]>())
+    }
+
+    #[test]
+    fn round_trip() {
+        let key = MasterKey::from_base64(&random_key()).unwrap();
+        let stored = key.encrypt("sk-secret").unwrap();
+        assert!(is_encrypted(&stored));
+        assert!(!stored.contains("sk-secret"));
+        assert_eq!(key.decrypt(&stored).unwrap(), "sk-secret");
+        // A fresh nonce every time
+        assert_ne!(key.encrypt("sk-secret").unwrap(), stored);
+    }
+
+    #[test]
+    fn wrong_key_fails() {
+        let stored = MasterKey::from_base64(&random_key()).unwrap().encrypt("sk-secret").unwrap();
+        let other = MasterKey::from_base64(&random_key()).unwrap();
+        assert!(other.decrypt(&stored).unwrap_err().0.contains("wrong master key"));
+    }
+
+    #[test]
+    fn truncated_payload_fails() {
+        let key = MasterKey::from_base64(&random_key()).unwrap();
+        let stored = key.encrypt("sk-secret").unwrap();
+        let payload = STANDARD.decode(&stored[ENCRYPTED_PREFIX.len()..]).unwrap();
+
+        let nonce_only = format!("{}{}", ENCRYPTED_PREFIX, STANDARD.encode(&payload[..NONCE_LEN]));
+        assert!(key.decrypt(&nonce_only).unwrap_err().0.contains("truncated"));
+        let cut_tag = format!("{}{}", ENCRYPTED_PREFIX, STANDARD.encode(&payload[..payload.len() - 1]));
+        assert!(key.decrypt(&cut_tag).is_err());
+        assert!(key.decrypt("enc:v1:not base64!").is_err());
+        assert!(key.decrypt("sk-plaintext").is_err());
+    }
+
+    #[test]
+    fn weak_keys_are_refused() {
+        assert!(MasterKey::from_base64("correct horse battery staple").is_err());
+        assert!(MasterKey::from_base64(&STANDARD.encode([7u8; 16])).is_err());
+        assert!(MasterKey::from_base64(&random_key()).is_ok());
+    }
+}
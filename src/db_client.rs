@@ -1,86 +1,230 @@
-use rusqlite::{Connection, Result, OptionalExtension, params};
+use rusqlite::{Connection, OpenFlags, Result, OptionalExtension, params};
+use rusqlite::types::Type;
 use chrono::Utc;
+use std::sync::OnceLock;
+use crate::config;
+use crate::crypto::{self, MasterKey};
+use crate::metrics;
+use crate::providers;
+use crate::selection::{Candidate, SelectionStrategy};
+
+// Raw TOKENS row for status reporting. `token` is the masked key, never the real one.
+pub struct TokenStatus {
+    pub id: i64,
+    pub token: String,
+    pub token_type: String,
+    pub triggered_on: Option<i64>,
+    pub delay_by_second: i64,
+    pub trouble_delay: bool,
+    pub seconds_until_available: i64, // 0 when get_next_token_by_llms could hand it out now
+}
 
 pub struct Token {
     pub id: i64,
     pub token: String,
     pub token_type: String,
+    pub base_url: Option<String>, // Endpoint for "openai_compat" tokens, e.g. https://api.groq.com/openai/v1
+    pub model: Option<String>,    // Default model for this token when the request doesn't name one
 }
 
-/// Get next token, optionally filtered by a list of LLM names (token_type).
-pub fn get_next_token_by_llms(llms: Option<&[&str]>) -> Result<Option<Token>> {
-    let conn = Connection::open("data.db")?;
-    let current_time = Utc::now().timestamp();
-
-    let (sql, params): (String, Vec<rusqlite::types::Value>) = if let Some(llms) = llms {
-        if llms.is_empty() {
-            (
-                "
-                SELECT id, token, token_type, triggered_on, delay_by_second, trouble_delay 
-                FROM TOKENS 
-                WHERE triggered_on IS NULL 
-                OR (triggered_on + delay_by_second) < ?
-                ORDER BY triggered_on ASC
-                LIMIT 1
-                ".to_string(),
-                vec![current_time.into()],
-            )
-        } else {
-            let placeholders = llms.iter().map(|_| "?".to_string()).collect::<Vec<_>>().join(",");
-            let sql = format!(
-                "
-                SELECT id, token, token_type, triggered_on, delay_by_second, trouble_delay 
-                FROM TOKENS 
-                WHERE (triggered_on IS NULL OR (triggered_on + delay_by_second) < ?)
-                AND token_type IN ({})
-                ORDER BY triggered_on ASC
-                LIMIT 1
-                ",
-                placeholders
-            );
-            let mut params: Vec<rusqlite::types::Value> = Vec::with_capacity(1 + llms.len());
-            params.push(current_time.into());
-            for llm in llms {
-                params.push(llm.to_string().into());
-            }
-            (sql, params)
+// Columns read into a Token, in order, see token_from_row
+const TOKEN_COLUMNS: &str = "id, token, token_type, base_url, model";
+
+fn token_from_row(row: &rusqlite::Row) -> Result<Token> {
+    Ok(Token {
+        id: row.get(0)?,
+        token: reveal_token_column(row, 1)?,
+        token_type: row.get(2)?,
+        base_url: row.get(3)?,
+        model: row.get(4)?,
+    })
+}
+
+// Open the token pool database configured by SAFE_TRIGGER_DB (data.db by default)
+fn open_db() -> Result<Connection> {
+    Connection::open(&config::get().db_path)
+}
+
+// Decrypt a TOKENS.token value read from the given column, so the plaintext key only ever lives in memory
+fn reveal_token_column(row: &rusqlite::Row, idx: usize) -> Result<String> {
+    let stored: String = row.get(idx)?;
+    crypto::reveal_token(&stored)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(idx, Type::Text, Box::new(e)))
+}
+
+/// How to treat the list of LLMs a request may use.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LlmStrategy {
+    Ordered, // Earlier types are preferred, later ones only used when none of those is available
+    Any,     // Every listed type is equal, the least recently used token wins
+}
+
+impl LlmStrategy {
+    /// Parse the `strategy` request option, "ordered" when absent.
+    pub fn parse(value: Option<&str>) -> Option<Self> {
+        match value.map(str::trim) {
+            None | Some("") | Some("ordered") => Some(Self::Ordered),
+            Some("any") => Some(Self::Any),
+            Some(_) => None,
+        }
+    }
+}
+
+/// Which tokens a request may use, and which it prefers.
+#[derive(Clone, Copy)]
+pub struct TokenQuery<'a> {
+    pub llms: Option<&'a [&'a str]>, // token_type filter; None or empty allows every type
+    pub strategy: LlmStrategy,
+    pub selection: &'a dyn SelectionStrategy, // Picks among the available tokens of the preferred type
+}
+
+// When a token can be handed out, with ?1 bound to the current time. Providers registered with
+// `cooldown: false` (see providers::ollama) are limited some other way, so their tokens are always
+// available unless marked as in trouble, which makes them sit out the trouble delay like any other token.
+fn off_cooldown() -> &'static str {
+    static OFF_COOLDOWN: OnceLock<String> = OnceLock::new();
+    OFF_COOLDOWN.get_or_init(|| {
+        let exempt: Vec<String> =
+            providers::all().iter().filter(|provider| !provider.cooldown).map(|provider| format!("'{}'", provider.token_type)).collect();
+        if exempt.is_empty() {
+            return "(triggered_on IS NULL OR (triggered_on + delay_by_second) < ?1)".to_string();
         }
-    } else {
-        (
-            "
-            SELECT id, token, token_type, triggered_on, delay_by_second, trouble_delay 
-            FROM TOKENS 
-            WHERE triggered_on IS NULL 
-            OR (triggered_on + delay_by_second) < ?
-            ORDER BY triggered_on ASC
-            LIMIT 1
-            ".to_string(),
-            vec![current_time.into()],
+        format!(
+            "(triggered_on IS NULL OR (triggered_on + delay_by_second) < ?1
+            OR (token_type IN ({}) AND COALESCE(trouble_delay, 0) = 0))",
+            exempt.join(",")
         )
-    };
+    })
+}
+
+// Another request may claim the picked token between selecting and claiming it; try again this often
+const MAX_CLAIM_ATTEMPTS: usize = 5;
 
+/// Get next token, optionally filtered by a list of LLM names (token_type).
+pub fn get_next_token_by_llms(query: TokenQuery) -> Result<Option<Token>> {
+    let _timer = metrics::db_timer("get_next_token");
+    let conn = open_db()?;
+
+    let mut sql = format!(
+        "SELECT id, token_type, triggered_on, delay_by_second, COALESCE(weight, 1)
+        FROM TOKENS
+        WHERE {}",
+        off_cooldown()
+    );
+    let llms = query.llms.filter(|llms| !llms.is_empty());
+    if let Some(llms) = llms {
+        let placeholders = llms.iter().map(|_| "?").collect::<Vec<_>>().join(",");
+        sql.push_str(&format!(" AND token_type IN ({})", placeholders));
+    }
     let mut stmt = conn.prepare(&sql)?;
-    let token = stmt.query_row(rusqlite::params_from_iter(params.iter()), |row| {
-        Ok(Token {
-            id: row.get(0)?,
-            token: row.get(1)?,
-            token_type: row.get(2)?,
-        })
-    }).optional()?;
 
-    // If we found a token, update its triggered_on timestamp
-    if let Some(token) = &token {
-        conn.execute(
-            "UPDATE TOKENS SET triggered_on = ? WHERE id = ?",
-            params![current_time, token.id],
+    for _ in 0..MAX_CLAIM_ATTEMPTS {
+        let current_time = Utc::now().timestamp();
+        let mut params: Vec<rusqlite::types::Value> = vec![current_time.into()];
+        params.extend(llms.unwrap_or_default().iter().map(|llm| llm.to_string().into()));
+        let mut rows: Vec<(String, Candidate)> = stmt
+            .query_map(rusqlite::params_from_iter(params.iter()), |row| {
+                Ok((row.get(1)?, Candidate {
+                    id: row.get(0)?,
+                    triggered_on: row.get(2)?,
+                    delay_by_second: row.get(3)?,
+                    weight: row.get(4)?,
+                }))
+            })?
+            .collect::<Result<_>>()?;
+
+        // Only the earliest listed type that has a free token competes
+        if let (Some(llms), LlmStrategy::Ordered) = (llms, query.strategy) {
+            let rank = |token_type: &str| llms.iter().position(|llm| *llm == token_type).unwrap_or(usize::MAX);
+            if let Some(best) = rows.iter().map(|(token_type, _)| rank(token_type)).min() {
+                rows.retain(|(token_type, _)| rank(token_type) == best);
+            }
+        }
+        if rows.is_empty() {
+            return Ok(None);
+        }
+
+        let candidates: Vec<Candidate> = rows.into_iter().map(|(_, candidate)| candidate).collect();
+        let index = query.selection.pick(&candidates).min(candidates.len() - 1);
+        let picked = &candidates[index];
+        let claimed_until = current_time + query.selection.cooldown_jitter(picked);
+        let claimed = conn.execute(
+            &format!("UPDATE TOKENS SET triggered_on = ?2 WHERE id = ?3 AND {}", off_cooldown()),
+            params![current_time, claimed_until, picked.id],
         )?;
+        if claimed == 1 {
+            let token = conn.query_row(
+                &format!("SELECT {} FROM TOKENS WHERE id = ?1", TOKEN_COLUMNS),
+                params![picked.id],
+                token_from_row,
+            )?;
+            return Ok(Some(token));
+        }
+    }
+    Ok(None)
+}
+
+// Columns added to TOKENS after the original schema, created on startup for existing databases
+const ADDED_TOKEN_COLUMNS: &[(&str, &str)] = &[
+    ("weight", "REAL NOT NULL DEFAULT 1"), // Relative share of traffic for the "weighted" selection strategy
+    ("base_url", "TEXT"),
+    ("model", "TEXT"),
+];
+
+/// Add TOKENS columns introduced after the original schema. Leaves a missing database alone.
+pub fn add_missing_token_columns() -> Result<()> {
+    let conn = Connection::open_with_flags(&config::get().db_path, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+    let mut stmt = conn.prepare("PRAGMA table_info(TOKENS)")?;
+    let existing = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<_>>>()?;
+    if existing.is_empty() {
+        return Ok(()); // No TOKENS table yet, /readyz reports it
+    }
+    for (name, column_type) in ADDED_TOKEN_COLUMNS {
+        if !existing.iter().any(|c| c.eq_ignore_ascii_case(name)) {
+            conn.execute(&format!("ALTER TABLE TOKENS ADD COLUMN {} {}", name, column_type), [])?;
+        }
     }
+    Ok(())
+}
 
-    Ok(token)
+/// Make SQLite reject TOKENS rows whose token_type isn't in `token_types`, on insert and on update.
+/// The triggers are recreated on every startup so they follow the provider registry. Returns how many
+/// existing rows already have an unknown type. Leaves a missing database alone.
+pub fn install_token_type_check(token_types: &[&str]) -> Result<i64> {
+    let conn = Connection::open_with_flags(&config::get().db_path, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+    let has_table: bool = conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'TOKENS')",
+        [],
+        |row| row.get(0),
+    )?;
+    if !has_table {
+        return Ok(0);
+    }
+    // Registry names are fixed identifiers, safe to inline as literals
+    let known = token_types.iter().map(|t| format!("'{}'", t)).collect::<Vec<_>>().join(", ");
+    let abort = format!("Unknown token_type, expected one of: {}", token_types.join(", "));
+    conn.execute_batch(&format!(
+        "DROP TRIGGER IF EXISTS TOKENS_KNOWN_TYPE_INSERT;
+        DROP TRIGGER IF EXISTS TOKENS_KNOWN_TYPE_UPDATE;
+        CREATE TRIGGER TOKENS_KNOWN_TYPE_INSERT BEFORE INSERT ON TOKENS
+        WHEN NEW.token_type NOT IN ({known})
+        BEGIN SELECT RAISE(ABORT, '{abort}'); END;
+        CREATE TRIGGER TOKENS_KNOWN_TYPE_UPDATE BEFORE UPDATE OF token_type ON TOKENS
+        WHEN NEW.token_type NOT IN ({known})
+        BEGIN SELECT RAISE(ABORT, '{abort}'); END;"
+    ))?;
+    conn.query_row(
+        &format!("SELECT COUNT(*) FROM TOKENS WHERE token_type NOT IN ({})", known),
+        [],
+        |row| row.get(0),
+    )
 }
 
 pub fn mark_token_trouble(token_id: i64) -> Result<()> {
-    let conn = Connection::open("data.db")?;
+    let _timer = metrics::db_timer("mark_token_trouble");
+    let conn = open_db()?;
     
     // Update trouble_delay to 1 and add 1 hour to delay_by_second
     conn.execute(
@@ -94,23 +238,10 @@ pub fn mark_token_trouble(token_id: i64) -> Result<()> {
     Ok(())
 }
 
-// Function to get token details by ID
-pub fn get_token_by_id(token_id: i64) -> Result<Option<Token>> {
-    let conn = Connection::open("data.db")?;
-    let mut stmt = conn.prepare("SELECT id, token, token_type FROM TOKENS WHERE id = ?")?;
-    let token = stmt.query_row(params![token_id], |row| {
-        Ok(Token {
-            id: row.get(0)?,
-            token: row.get(1)?,
-            token_type: row.get(2)?,
-        })
-    }).optional()?;
-    Ok(token)
-}
-
 // Function to check if a token is marked as in trouble
 pub fn is_token_in_trouble(token_id: i64) -> Result<bool> {
-    let conn = Connection::open("data.db")?;
+    let _timer = metrics::db_timer("is_token_in_trouble");
+    let conn = open_db()?;
     let mut stmt = conn.prepare("SELECT trouble_delay FROM TOKENS WHERE id = ?")?;
     
     let result = stmt.query_row(params![token_id], |row| {
@@ -126,7 +257,8 @@ pub fn is_token_in_trouble(token_id: i64) -> Result<bool> {
 }
 
 pub fn clear_token_trouble(token_id: i64) -> Result<()> {
-    let conn = Connection::open("data.db")?;
+    let _timer = metrics::db_timer("clear_token_trouble");
+    let conn = open_db()?;
 
     // First, check if the token has trouble_delay = 1
     let mut stmt = conn.prepare("SELECT trouble_delay FROM TOKENS WHERE id = ?")?;
@@ -145,3 +277,215 @@ pub fn clear_token_trouble(token_id: i64) -> Result<()> {
     
     Ok(())
 }
+
+/// Re-encrypt every token with `new_key`. Values encrypted under one of `old_keys` are decrypted first,
+/// plaintext values are simply encrypted. Returns the number of rows rewritten.
+pub fn reencrypt_tokens(old_keys: &[MasterKey], new_key: &MasterKey) -> Result<usize> {
+    reencrypt_tokens_in(&mut open_db()?, old_keys, new_key)
+}
+
+fn reencrypt_tokens_in(conn: &mut Connection, old_keys: &[MasterKey], new_key: &MasterKey) -> Result<usize> {
+    let tx = conn.transaction()?;
+
+    let rows: Vec<(i64, String)> = {
+        let mut stmt = tx.prepare("SELECT id, token FROM TOKENS")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect::<Result<Vec<_>>>()?
+    };
+
+    let mut updated = 0;
+    for (id, stored) in rows {
+        let encrypted = crypto::reencrypt(&stored, old_keys, new_key).map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(1, Type::Text, Box::new(crypto::CryptoError(
+                format!("Token {}: {}", id, e)
+            )))
+        })?;
+        tx.execute("UPDATE TOKENS SET token = ? WHERE id = ?", params![encrypted, id])?;
+        updated += 1;
+    }
+
+    tx.commit()?;
+    Ok(updated)
+}
+
+// Number of TOKENS rows still holding a plaintext key
+pub fn count_plaintext_tokens() -> Result<i64> {
+    let conn = open_db()?;
+    conn.query_row(
+        "SELECT COUNT(*) FROM TOKENS WHERE substr(token, 1, ?1) != ?2",
+        params![crypto::ENCRYPTED_PREFIX.len() as i64, crypto::ENCRYPTED_PREFIX],
+        |row| row.get(0),
+    )
+}
+
+/// Count tokens per token_type in each state: `in_trouble` (trouble_delay set), `available`
+/// (cooldown elapsed) or `cooling_down`. Each token is counted in exactly one state.
+pub fn count_tokens_by_state() -> Result<Vec<(String, String, i64)>> {
+    let _timer = metrics::db_timer("count_tokens_by_state");
+    let conn = open_db()?;
+    let mut stmt = conn.prepare(&format!(
+        "SELECT token_type,
+            CASE
+                WHEN COALESCE(trouble_delay, 0) = 1 THEN 'in_trouble'
+                WHEN {} THEN 'available'
+                ELSE 'cooling_down'
+            END AS state,
+            COUNT(*)
+        FROM TOKENS
+        GROUP BY token_type, state",
+        off_cooldown()
+    ))?;
+    let rows = stmt.query_map(params![Utc::now().timestamp()], |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+    })?;
+    rows.collect()
+}
+
+// Columns the token pool code relies on
+const REQUIRED_TOKEN_COLUMNS: &[&str] = &["id", "token", "token_type", "triggered_on", "delay_by_second", "trouble_delay"];
+
+/// Check the token database exists and has the TOKENS schema, without creating the file if it's missing.
+/// Returns the names of missing columns (all of them if the table doesn't exist).
+pub fn check_schema() -> Result<Vec<String>> {
+    let _timer = metrics::db_timer("check_schema");
+    let conn = Connection::open_with_flags(&config::get().db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut stmt = conn.prepare("PRAGMA table_info(TOKENS)")?;
+    let existing = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<_>>>()?;
+    Ok(REQUIRED_TOKEN_COLUMNS
+        .iter()
+        .filter(|column| !existing.iter().any(|c| c.eq_ignore_ascii_case(column)))
+        .map(|column| column.to_string())
+        .collect())
+}
+
+/// Per token_type: (token_type, usable, total), where usable means not marked as in trouble.
+pub fn count_usable_tokens() -> Result<Vec<(String, i64, i64)>> {
+    let _timer = metrics::db_timer("count_usable_tokens");
+    let conn = Connection::open_with_flags(&config::get().db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut stmt = conn.prepare(
+        "SELECT token_type, SUM(CASE WHEN COALESCE(trouble_delay, 0) = 1 THEN 0 ELSE 1 END), COUNT(*)
+        FROM TOKENS
+        GROUP BY token_type",
+    )?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+    rows.collect()
+}
+
+/// Every token with its cooldown state, ordered by id. Keys are masked.
+pub fn list_token_statuses() -> Result<Vec<TokenStatus>> {
+    let _timer = metrics::db_timer("list_token_statuses");
+    let conn = open_db()?;
+    // Same availability rule as get_next_token_by_llms and token_availability
+    let mut stmt = conn.prepare(&format!(
+        "SELECT id, token, token_type, triggered_on, COALESCE(delay_by_second, 0), COALESCE(trouble_delay, 0),
+            CASE WHEN {} THEN 0 ELSE MAX(COALESCE(triggered_on + delay_by_second + 1 - ?1, 0), 0) END
+        FROM TOKENS
+        ORDER BY id",
+        off_cooldown()
+    ))?;
+    let rows = stmt.query_map(params![Utc::now().timestamp()], |row| {
+        let stored: String = row.get(1)?;
+        // Decrypt just long enough to mask, so encrypted keys still show a recognisable prefix
+        let masked = crypto::reveal_token(&stored)
+            .map(|plaintext| crypto::mask_token(&plaintext))
+            .unwrap_or_else(|_| crypto::mask_token(&stored));
+        Ok(TokenStatus {
+            id: row.get(0)?,
+            token: masked,
+            token_type: row.get(2)?,
+            triggered_on: row.get(3)?,
+            delay_by_second: row.get(4)?,
+            trouble_delay: row.get::<_, i64>(5)? == 1,
+            seconds_until_available: row.get(6)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// Pick a usable token of `token_type` for auxiliary calls such as embeddings, which have their own
+/// provider quota, without claiming it: triggered_on is left alone so chat cooldowns are unaffected.
+pub fn peek_token_by_type(token_type: &str) -> Result<Option<Token>> {
+    let _timer = metrics::db_timer("peek_token_by_type");
+    let conn = open_db()?;
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM TOKENS
+        WHERE token_type = ? AND COALESCE(trouble_delay, 0) = 0
+        ORDER BY RANDOM()
+        LIMIT 1",
+        TOKEN_COLUMNS
+    ))?;
+    stmt.query_row(params![token_type], token_from_row).optional()
+}
+
+/// How the pool looks for a set of LLMs: the number of matching tokens, and the seconds until the
+/// soonest of them comes off cooldown (0 if one is free now, None if no token matches at all).
+pub fn token_availability(llms: Option<&[&str]>) -> Result<(i64, Option<i64>)> {
+    let _timer = metrics::db_timer("token_availability");
+    let conn = open_db()?;
+    let llms = llms.filter(|l| !l.is_empty());
+    let mut values: Vec<rusqlite::types::Value> = vec![Utc::now().timestamp().into()];
+    let type_filter = match llms {
+        Some(llms) => {
+            values.extend(llms.iter().map(|llm| llm.to_string().into()));
+            format!("WHERE token_type IN ({})", llms.iter().map(|_| "?").collect::<Vec<_>>().join(","))
+        }
+        None => String::new(),
+    };
+    // get_next_token_by_llms hands a token out once triggered_on + delay_by_second is strictly in the past
+    conn.query_row(
+        &format!(
+            "SELECT COUNT(*), MIN(CASE WHEN {} THEN 0 ELSE MAX(COALESCE(triggered_on + delay_by_second + 1 - ?1, 0), 0) END)
+            FROM TOKENS {}",
+            off_cooldown(), type_filter
+        ),
+        rusqlite::params_from_iter(values.iter()),
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    fn key() -> MasterKey {
+        MasterKey::from_base64(&STANDARD.encode(rand::random::<[u8; 32]>())).unwrap()
+    }
+
+    fn tokens_db(values: &[&str]) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE TOKENS (id INTEGER PRIMARY KEY, token TEXT)").unwrap();
+        for value in values {
+            conn.execute("INSERT INTO TOKENS (token) VALUES (?1)", params![value]).unwrap();
+        }
+        conn
+    }
+
+    fn stored(conn: &Connection) -> Vec<String> {
+        let mut stmt = conn.prepare("SELECT token FROM TOKENS ORDER BY id").unwrap();
+        stmt.query_map([], |row| row.get(0)).unwrap().collect::<Result<_>>().unwrap()
+    }
+
+    #[test]
+    fn reencrypt_moves_every_token_to_the_new_key() {
+        let (old, new) = (key(), key());
+        let mut conn = tokens_db(&["sk-plain", &old.encrypt("sk-old").unwrap(), &new.encrypt("sk-new").unwrap()]);
+
+        assert_eq!(reencrypt_tokens_in(&mut conn, &[old], &new).unwrap(), 3);
+        let revealed: Vec<String> = stored(&conn).iter().map(|value| new.decrypt(value).unwrap()).collect();
+        assert_eq!(revealed, ["sk-plain", "sk-old", "sk-new"]);
+    }
+
+    #[test]
+    fn reencrypt_without_the_old_key_changes_nothing() {
+        let (old, new) = (key(), key());
+        let mut conn = tokens_db(&["sk-plain", &old.encrypt("sk-old").unwrap()]);
+        let before = stored(&conn);
+
+        let error = reencrypt_tokens_in(&mut conn, &[], &new).unwrap_err();
+        assert!(error.to_string().contains("Token 2"), "unexpected error: {}", error);
+        assert_eq!(stored(&conn), before);
+    }
+}
@@ -0,0 +1,161 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::OnceLock;
+use tracing::{info, warn};
+
+use crate::config;
+
+// Generation parameters: provider-neutral sampling settings on a request, mapped by each provider's
+// client to its own fields. Anything the request leaves out comes from the model's entry in the model
+// defaults file (SAFE_TRIGGER_MODEL_DEFAULTS), and after that from the provider itself.
+//
+// The defaults file maps model names to parameters, e.g.
+//   { "gemini-2.5-flash-preview-04-17": { "temperature": 0.2, "max_tokens": 2048 } }
+//
+// A response_schema asks for structured output: providers that support it are told to answer with JSON
+// matching the schema, and every answer is checked against it before it is accepted (see parse_answer).
+
+const MAX_TEMPERATURE: f64 = 2.0;
+const MAX_STOP_SEQUENCES: usize = 4; // The smallest limit among the supported APIs
+
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct GenerationParams {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,   // 0 to 2
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,    // Upper bound on the answer's length, at least 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,         // Nucleus sampling, above 0 and at most 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,  // Generation ends before any of these
+    // A JSON Schema the answer must match. Set per request only, never from the defaults file.
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub response_schema: Option<Value>,
+}
+
+/// `stop` on a request: one sequence (the only form a GET query string can carry) or a list.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum Stop {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Stop {
+    pub fn into_vec(self) -> Vec<String> {
+        match self {
+            Stop::One(sequence) => vec![sequence],
+            Stop::Many(sequences) => sequences,
+        }
+    }
+}
+
+impl GenerationParams {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Check every set parameter is in range, describing the first one that isn't.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(temperature) = self.temperature {
+            if !(0.0..=MAX_TEMPERATURE).contains(&temperature) {
+                return Err(format!("temperature must be between 0 and {}, got {}", MAX_TEMPERATURE, temperature));
+            }
+        }
+        if self.max_tokens == Some(0) {
+            return Err("max_tokens must be at least 1".to_string());
+        }
+        if let Some(top_p) = self.top_p {
+            if !(top_p > 0.0 && top_p <= 1.0) {
+                return Err(format!("top_p must be above 0 and at most 1, got {}", top_p));
+            }
+        }
+        if let Some(stop) = &self.stop {
+            if stop.len() > MAX_STOP_SEQUENCES {
+                return Err(format!("stop takes at most {} sequences, got {}", MAX_STOP_SEQUENCES, stop.len()));
+            }
+            if stop.iter().any(String::is_empty) {
+                return Err("stop sequences may not be empty".to_string());
+            }
+        }
+        if let Some(schema) = &self.response_schema {
+            if !schema.is_object() {
+                return Err("response_schema must be a JSON Schema object".to_string());
+            }
+            jsonschema::validator_for(schema).map_err(|e| format!("response_schema is not a valid JSON Schema: {}", e))?;
+        }
+        Ok(())
+    }
+
+    /// These parameters, with unset ones taken from `defaults`.
+    pub fn or(&self, defaults: Option<&GenerationParams>) -> GenerationParams {
+        let Some(defaults) = defaults else { return self.clone() };
+        GenerationParams {
+            temperature: self.temperature.or(defaults.temperature),
+            max_tokens: self.max_tokens.or(defaults.max_tokens),
+            top_p: self.top_p.or(defaults.top_p),
+            stop: self.stop.clone().or_else(|| defaults.stop.clone()),
+            response_schema: self.response_schema.clone(),
+        }
+    }
+}
+
+/// `response_schema` as sent on a request. A GET query string can only carry it as JSON text.
+pub fn response_schema_from(value: Value) -> Value {
+    match value {
+        Value::String(text) => serde_json::from_str(&text).unwrap_or(Value::String(text)),
+        value => value,
+    }
+}
+
+// Models without a native JSON mode like to wrap their JSON in a markdown code block
+fn strip_code_fence(text: &str) -> &str {
+    let trimmed = text.trim();
+    let Some(inner) = trimmed.strip_prefix("```").and_then(|rest| rest.strip_suffix("```")) else { return trimmed };
+    // Drop the language tag, e.g. ```json
+    inner.split_once('\n').map_or(inner, |(_, body)| body).trim()
+}
+
+/// Parse an answer given for `schema` and check it matches, describing what is wrong if it doesn't.
+pub fn parse_answer(text: &str, schema: &Value) -> Result<Value, String> {
+    let parsed: Value = serde_json::from_str(strip_code_fence(text)).map_err(|e| format!("answer is not JSON: {}", e))?;
+    let validator = jsonschema::validator_for(schema).map_err(|e| format!("invalid response_schema: {}", e))?;
+    if let Err(e) = validator.validate(&parsed) {
+        let path = e.instance_path.to_string();
+        return Err(if path.is_empty() { e.to_string() } else { format!("{} at {}", e, path) });
+    }
+    Ok(parsed)
+}
+
+// Read once, the first time a model's defaults are needed. A missing file means no defaults.
+fn model_defaults() -> &'static HashMap<String, GenerationParams> {
+    static DEFAULTS: OnceLock<HashMap<String, GenerationParams>> = OnceLock::new();
+    DEFAULTS.get_or_init(|| {
+        let path = &config::get().model_defaults_path;
+        let Ok(contents) = fs::read_to_string(path) else { return HashMap::new() };
+        let mut defaults: HashMap<String, GenerationParams> = match serde_json::from_str(&contents) {
+            Ok(defaults) => defaults,
+            Err(e) => {
+                warn!(path = %path, error = %e, "Ignoring invalid model defaults file");
+                return HashMap::new();
+            }
+        };
+        defaults.retain(|model, params| match params.validate() {
+            Ok(()) => true,
+            Err(e) => {
+                warn!(path = %path, model = %model, error = %e, "Ignoring invalid model defaults");
+                false
+            }
+        });
+        info!(path = %path, models = defaults.len(), "Loaded model defaults");
+        defaults
+    })
+}
+
+/// Default generation parameters for `model`, if the defaults file has an entry for it.
+pub fn defaults_for(model: &str) -> Option<&'static GenerationParams> {
+    model_defaults().get(model)
+}
@@ -0,0 +1,91 @@
+use axum::{http::StatusCode, Json};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use tracing::warn;
+
+use crate::{config, db_client};
+
+#[derive(Serialize)]
+pub struct HealthResponse {
+    status: &'static str,
+}
+
+#[derive(Serialize)]
+pub struct Check {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ProviderCheck {
+    ok: bool,
+    usable: i64, // Tokens not marked as in trouble
+    total: i64,
+}
+
+#[derive(Serialize)]
+pub struct ReadinessResponse {
+    status: &'static str,
+    database: Check,
+    schema: Check,
+    providers: BTreeMap<String, ProviderCheck>,
+}
+
+fn check_ok() -> Check {
+    Check { ok: true, error: None }
+}
+
+fn check_failed(error: String) -> Check {
+    Check { ok: false, error: Some(error) }
+}
+
+// GET /healthz: the process is up and serving HTTP
+pub async fn handle_healthz() -> Json<HealthResponse> {
+    Json(HealthResponse { status: "ok" })
+}
+
+// GET /readyz: the instance can actually serve chat requests. 503 if any check fails.
+pub async fn handle_readyz() -> (StatusCode, Json<ReadinessResponse>) {
+    let (mut database, schema) = match db_client::check_schema() {
+        Ok(missing) if missing.is_empty() => (check_ok(), check_ok()),
+        Ok(missing) => (check_ok(), check_failed(format!("TOKENS is missing columns: {}", missing.join(", ")))),
+        Err(e) => (check_failed(e.to_string()), check_failed("Database unreachable".to_string())),
+    };
+
+    let mut providers = BTreeMap::new();
+    if database.ok && schema.ok {
+        match db_client::count_usable_tokens() {
+            Ok(counts) => {
+                // Without an explicit provider list, every token type in the pool must be usable
+                let configured = &config::get().providers;
+                let required: Vec<String> = if configured.is_empty() {
+                    counts.iter().map(|(token_type, _, _)| token_type.clone()).collect()
+                } else {
+                    configured.clone()
+                };
+                for token_type in required {
+                    let (usable, total) = counts
+                        .iter()
+                        .find(|(t, _, _)| *t == token_type)
+                        .map(|(_, usable, total)| (*usable, *total))
+                        .unwrap_or((0, 0));
+                    providers.insert(token_type, ProviderCheck { ok: usable > 0, usable, total });
+                }
+            }
+            Err(e) => {
+                warn!(error = %e, "Failed to count usable tokens for readiness check");
+                database = check_failed(e.to_string());
+            }
+        }
+    }
+
+    let ready = database.ok && schema.ok && !providers.is_empty() && providers.values().all(|p| p.ok);
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(ReadinessResponse {
+        status: if ready { "ready" } else { "not_ready" },
+        database,
+        schema,
+        providers,
+    }))
+}
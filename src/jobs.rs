@@ -0,0 +1,381 @@
+use axum::{
+    extract::{Json, Path, Query, State},
+};
+use chrono::Utc;
+use rusqlite::{types::Type, Connection, OptionalExtension, Result, params};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{sync::Arc, time::Duration};
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::crypto::{self, MasterKey};
+use crate::{check_access_token, config, handle_chat_request, webhooks, AppState, ChatRequest, ErrorResponse};
+
+// Asynchronous chat jobs: POST /api/jobs stores the request in the JOBS table and returns at once,
+// a pool of workers runs queued jobs through the normal chat path, GET /api/jobs/{id} reports the outcome.
+
+// How long an idle worker sleeps before checking JOBS again without being notified
+const IDLE_POLL_SECONDS: u64 = 5;
+
+fn open() -> Result<Connection> {
+    let conn = Connection::open(&config::get().jobs_db_path)?;
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS JOBS (
+            id TEXT PRIMARY KEY,
+            status TEXT NOT NULL,           -- queued, running, succeeded or failed
+            request TEXT NOT NULL,          -- ChatRequest as JSON, without the access token
+            result TEXT,                    -- ChatResponse as JSON when succeeded
+            error TEXT,                     -- Error message when failed
+            created_at INTEGER NOT NULL,
+            started_at INTEGER,
+            finished_at INTEGER
+        );
+        CREATE INDEX IF NOT EXISTS idx_jobs_status_created_at ON JOBS (status, created_at);",
+    )?;
+    add_missing_columns(&conn)?;
+    Ok(conn)
+}
+
+// Columns added to JOBS after the original schema
+const ADDED_COLUMNS: &[(&str, &str)] = &[
+    ("callback_url", "TEXT"),
+    ("callback_secret", "TEXT"),    // HMAC signing secret, encrypted when a master key is configured
+    ("callback_status", "TEXT"),    // pending, delivered or failed; NULL without a callback_url
+    ("callback_attempts", "INTEGER"),
+    ("callback_error", "TEXT"),     // Last delivery error
+];
+
+fn add_missing_columns(conn: &Connection) -> Result<()> {
+    let mut stmt = conn.prepare("PRAGMA table_info(JOBS)")?;
+    let existing = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<_>>>()?;
+    for (name, column_type) in ADDED_COLUMNS {
+        if !existing.iter().any(|c| c.eq_ignore_ascii_case(name)) {
+            conn.execute(&format!("ALTER TABLE JOBS ADD COLUMN {} {}", name, column_type), [])?;
+        }
+    }
+    Ok(())
+}
+
+fn insert_job(id: &str, request_json: &str, callback: Option<&Callback>) -> Result<()> {
+    let conn = open()?;
+    conn.execute(
+        "INSERT INTO JOBS (id, status, request, created_at, callback_url, callback_secret, callback_status)
+         VALUES (?1, 'queued', ?2, ?3, ?4, ?5, ?6)",
+        params![
+            id,
+            request_json,
+            Utc::now().timestamp(),
+            callback.map(|c| c.url.as_str()),
+            callback.and_then(|c| c.secret.as_deref()),
+            callback.map(|_| "pending"),
+        ],
+    )?;
+    Ok(())
+}
+
+/// Encrypt every stored callback secret under `new_key`, for the rekey command, so pending callbacks can still
+/// be signed after a key rotation. Returns the number of secrets rewritten.
+pub fn reencrypt_callback_secrets(old_keys: &[MasterKey], new_key: &MasterKey) -> Result<usize> {
+    reencrypt_callback_secrets_in(&mut open()?, old_keys, new_key)
+}
+
+fn reencrypt_callback_secrets_in(conn: &mut Connection, old_keys: &[MasterKey], new_key: &MasterKey) -> Result<usize> {
+    let tx = conn.transaction()?;
+    let rows: Vec<(String, String)> = {
+        let mut stmt = tx.prepare("SELECT id, callback_secret FROM JOBS WHERE callback_secret IS NOT NULL")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect::<Result<Vec<_>>>()?
+    };
+    for (id, stored) in &rows {
+        let encrypted = crypto::reencrypt(stored, old_keys, new_key).map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(1, Type::Text, Box::new(crypto::CryptoError(
+                format!("Callback secret of job {}: {}", id, e)
+            )))
+        })?;
+        tx.execute("UPDATE JOBS SET callback_secret = ?1 WHERE id = ?2", params![encrypted, id])?;
+    }
+    tx.commit()?;
+    Ok(rows.len())
+}
+
+/// Where to deliver a finished job's result, and the secret to sign it with.
+pub struct Callback {
+    pub url: String,
+    pub secret: Option<String>, // As stored, see crypto::reveal_token
+}
+
+/// The callback of a finished job whose delivery is still pending, with the attempts made so far.
+pub fn pending_callback(id: &str) -> Result<Option<(Callback, u32)>> {
+    let conn = open()?;
+    conn.query_row(
+        "SELECT callback_url, callback_secret, COALESCE(callback_attempts, 0) FROM JOBS
+         WHERE id = ?1 AND callback_status = 'pending' AND status IN ('succeeded', 'failed')",
+        params![id],
+        |row| Ok((Callback { url: row.get(0)?, secret: row.get(1)? }, row.get(2)?)),
+    ).optional()
+}
+
+/// Finished jobs whose callback was not delivered yet, e.g. because the server restarted mid-retry.
+pub fn pending_callback_job_ids() -> Result<Vec<String>> {
+    let conn = open()?;
+    let mut stmt = conn.prepare(
+        "SELECT id FROM JOBS WHERE callback_status = 'pending' AND status IN ('succeeded', 'failed') ORDER BY finished_at",
+    )?;
+    let ids = stmt.query_map([], |row| row.get(0))?.collect();
+    ids
+}
+
+pub fn set_callback_status(id: &str, status: &str, attempts: u32, error: Option<&str>) -> Result<()> {
+    let conn = open()?;
+    conn.execute(
+        "UPDATE JOBS SET callback_status = ?1, callback_attempts = ?2, callback_error = ?3 WHERE id = ?4",
+        params![status, attempts, error, id],
+    )?;
+    Ok(())
+}
+
+// Atomically move the oldest queued job to running, returning (id, request JSON)
+fn claim_next_job() -> Result<Option<(String, String)>> {
+    let conn = open()?;
+    conn.query_row(
+        "UPDATE JOBS SET status = 'running', started_at = ?1
+        WHERE id = (SELECT id FROM JOBS WHERE status = 'queued' ORDER BY created_at, rowid LIMIT 1)
+        RETURNING id, request",
+        params![Utc::now().timestamp()],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ).optional()
+}
+
+fn finish_job(id: &str, outcome: &Result<String, String>) -> Result<()> {
+    let conn = open()?;
+    let (status, result, error) = match outcome {
+        Ok(result) => ("succeeded", Some(result.as_str()), None),
+        Err(error) => ("failed", None, Some(error.as_str())),
+    };
+    conn.execute(
+        "UPDATE JOBS SET status = ?1, result = ?2, error = ?3, finished_at = ?4 WHERE id = ?5",
+        params![status, result, error, Utc::now().timestamp(), id],
+    )?;
+    Ok(())
+}
+
+/// Put jobs that were running when the server stopped back in the queue. Call before starting workers.
+pub fn requeue_interrupted_jobs() -> Result<usize> {
+    let conn = open()?;
+    conn.execute("UPDATE JOBS SET status = 'queued', started_at = NULL WHERE status = 'running'", [])
+}
+
+// Drop finished jobs older than the configured retention
+fn prune_finished_jobs(days: u64) -> Result<usize> {
+    let conn = open()?;
+    let cutoff = Utc::now().timestamp() - (days as i64) * 86_400;
+    conn.execute(
+        "DELETE FROM JOBS WHERE status IN ('succeeded', 'failed') AND finished_at < ?1",
+        params![cutoff],
+    )
+}
+
+#[derive(Serialize)]
+pub struct Job {
+    id: String,
+    status: String,
+    created_at: i64,
+    started_at: Option<i64>,
+    finished_at: Option<i64>,
+    result: Option<Value>, // Same shape as a successful /api/chat response
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    callback_status: Option<String>, // Only for jobs submitted with a callback_url
+}
+
+pub fn get_job(id: &str) -> Result<Option<Job>> {
+    let conn = open()?;
+    conn.query_row(
+        "SELECT id, status, created_at, started_at, finished_at, result, error, callback_status FROM JOBS WHERE id = ?1",
+        params![id],
+        |row| {
+            let result: Option<String> = row.get(5)?;
+            Ok(Job {
+                id: row.get(0)?,
+                status: row.get(1)?,
+                created_at: row.get(2)?,
+                started_at: row.get(3)?,
+                finished_at: row.get(4)?,
+                result: result.and_then(|r| serde_json::from_str(&r).ok()),
+                error: row.get(6)?,
+                callback_status: row.get(7)?,
+            })
+        },
+    ).optional()
+}
+
+// Run one claimed job through the chat path; the job ID doubles as its request ID in LOGS
+async fn execute_job(state: Arc<AppState>, id: String, request_json: String) {
+    let outcome = match serde_json::from_str::<ChatRequest>(&request_json) {
+        Ok(request) => {
+            let Json(response) = handle_chat_request(state, request, &id).await;
+            match response {
+                Ok(chat_response) => serde_json::to_string(&chat_response).map_err(|e| e.to_string()),
+                Err(e) => Err(e.error),
+            }
+        }
+        Err(e) => Err(format!("Invalid stored job request: {}", e)),
+    };
+
+    match &outcome {
+        Ok(_) => info!("Job succeeded"),
+        Err(e) => warn!(error = %e, "Job failed"),
+    }
+    if let Err(e) = finish_job(&id, &outcome) {
+        error!(error = %e, "Failed to record job result");
+        return;
+    }
+    // Delivered in the background so the worker can move on to the next job
+    tokio::spawn(webhooks::deliver(id).in_current_span());
+}
+
+/// One job worker: claims queued jobs one at a time until the process exits.
+pub async fn run_worker(state: Arc<AppState>, worker: usize) {
+    loop {
+        match claim_next_job() {
+            Ok(Some((id, request_json))) => {
+                let span = info_span!("job", worker, request_id = %id);
+                execute_job(state.clone(), id, request_json).instrument(span).await;
+            }
+            Ok(None) => {
+                // Woken early when a job is submitted
+                let _ = tokio::time::timeout(Duration::from_secs(IDLE_POLL_SECONDS), state.job_notify.notified()).await;
+            }
+            Err(e) => {
+                warn!(worker, error = %e, "Failed to claim next job");
+                tokio::time::sleep(Duration::from_secs(IDLE_POLL_SECONDS)).await;
+            }
+        }
+    }
+}
+
+/// Hourly cleanup of finished jobs past SAFE_TRIGGER_JOB_RETENTION_DAYS.
+pub async fn run_cleanup_task() {
+    let days = config::get().job_retention_days;
+    let mut interval = tokio::time::interval(Duration::from_secs(3600));
+    loop {
+        interval.tick().await;
+        match prune_finished_jobs(days) {
+            Ok(0) => {}
+            Ok(deleted) => info!(deleted, "Pruned finished jobs"),
+            Err(e) => warn!(error = %e, "Failed to prune finished jobs"),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct JobSubmitted {
+    job_id: String,
+    status: &'static str,
+}
+
+#[derive(Deserialize)]
+pub struct SubmitJobRequest {
+    #[serde(flatten)]
+    chat: ChatRequest,
+    callback_url: Option<String>,    // POSTed the job result once it finishes
+    callback_secret: Option<String>, // Signs callbacks with HMAC-SHA256, see webhooks.rs
+}
+
+// Check the callback fields and prepare them for storage
+async fn callback_from(request: &SubmitJobRequest) -> Result<Option<Callback>, String> {
+    let Some(url) = &request.callback_url else {
+        return match request.callback_secret {
+            Some(_) => Err("callback_secret requires a callback_url".to_string()),
+            None => Ok(None),
+        };
+    };
+    match reqwest::Url::parse(url) {
+        Ok(parsed) if parsed.scheme() == "http" || parsed.scheme() == "https" => {
+            webhooks::resolve_callback(&parsed).await.map_err(|(error, _)| error)?;
+        }
+        _ => return Err(format!("callback_url must be an absolute http or https URL, got {:?}", url)),
+    }
+    let secret = match (&request.callback_secret, crypto::master_key()) {
+        (Some(secret), Some(key)) => Some(key.encrypt(secret).map_err(|e| e.0)?),
+        (secret, _) => secret.clone(),
+    };
+    Ok(Some(Callback { url: url.clone(), secret }))
+}
+
+// POST /api/jobs: same body as POST /api/chat, plus optional callback_url and callback_secret
+pub async fn handle_submit_job(
+    State(state): State<Arc<AppState>>,
+    Json(submission): Json<SubmitJobRequest>,
+) -> Json<Result<JobSubmitted, ErrorResponse>> {
+    let request = &submission.chat;
+    if let Err(e) = check_access_token(request.access_token.as_deref()) {
+        return Json(Err(e));
+    }
+    let callback = match callback_from(&submission).await {
+        Ok(callback) => callback,
+        Err(error) => return Json(Err(ErrorResponse { error })),
+    };
+
+    let job_id = uuid::Uuid::new_v4().to_string();
+    let request_json = match serde_json::to_string(request) {
+        Ok(json) => json,
+        Err(e) => return Json(Err(ErrorResponse { error: format!("Failed to encode job request: {}", e) })),
+    };
+    if let Err(e) = insert_job(&job_id, &request_json, callback.as_ref()) {
+        return Json(Err(ErrorResponse { error: format!("Job database error: {}", e) }));
+    }
+
+    info!(request_id = %job_id, "Job queued");
+    state.job_notify.notify_one();
+    Json(Ok(JobSubmitted { job_id, status: "queued" }))
+}
+
+#[derive(Deserialize)]
+pub struct JobQuery {
+    access_token: Option<String>,
+}
+
+// GET /api/jobs/{id}
+pub async fn handle_get_job(
+    Path(id): Path<String>,
+    Query(params): Query<JobQuery>,
+) -> Json<Result<Job, ErrorResponse>> {
+    if let Err(e) = check_access_token(params.access_token.as_deref()) {
+        return Json(Err(e));
+    }
+
+    match get_job(&id) {
+        Ok(Some(job)) => Json(Ok(job)),
+        Ok(None) => Json(Err(ErrorResponse { error: format!("Job {} not found", id) })),
+        Err(e) => Json(Err(ErrorResponse { error: format!("Job database error: {}", e) })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    fn key() -> MasterKey {
+        MasterKey::from_base64(&STANDARD.encode(rand::random::<[u8; 32]>())).unwrap()
+    }
+
+    #[test]
+    fn reencrypt_moves_callback_secrets_to_the_new_key() {
+        let (old, new) = (key(), key());
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE JOBS (id TEXT PRIMARY KEY, callback_secret TEXT)").unwrap();
+        for (id, secret) in [("a", Some("plain".to_string())), ("b", Some(old.encrypt("old").unwrap())), ("c", None)] {
+            conn.execute("INSERT INTO JOBS (id, callback_secret) VALUES (?1, ?2)", params![id, secret]).unwrap();
+        }
+
+        assert_eq!(reencrypt_callback_secrets_in(&mut conn, &[old], &new).unwrap(), 2);
+        let mut stmt = conn.prepare("SELECT callback_secret FROM JOBS ORDER BY id").unwrap();
+        let stored: Vec<Option<String>> = stmt.query_map([], |row| row.get(0)).unwrap().collect::<Result<_>>().unwrap();
+        let revealed: Vec<Option<String>> = stored.iter().map(|value| value.as_deref().map(|v| new.decrypt(v).unwrap())).collect();
+        assert_eq!(revealed, [Some("plain".to_string()), Some("old".to_string()), None]);
+    }
+}
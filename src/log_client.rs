@@ -1,10 +1,86 @@
-use rusqlite::{Connection, Result, params};
-use chrono::Local;
+use rusqlite::{Connection, Result, params, params_from_iter};
+use rusqlite::types::Value;
+use chrono::{Duration as ChronoDuration, Local};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+use crate::config::{self, Config};
+use crate::crypto;
+use crate::db_client;
+
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+// Filters for query_logs. Times use the same "YYYY-MM-DD HH:MM:SS" local format as LOGS.time.
+pub struct LogFilter {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub token_type: Option<String>,
+    pub token_id: Option<i64>,
+    pub caller: Option<String>,
+    pub request_id: Option<String>,
+    pub success: Option<bool>,
+    pub text: Option<String>, // Substring match on prompt, system_prompt and response
+    pub limit: u32,
+    pub offset: u32,
+}
+
+// One LOGS row as returned by the admin API. Rows written before a column existed have it as null.
+#[derive(Serialize)]
+pub struct LogEntry {
+    pub id: i64,
+    pub time: Option<String>,
+    pub token_type: Option<String>,
+    pub token_id: Option<i64>,
+    pub token: Option<String>,
+    pub caller: Option<String>,
+    pub success: Option<bool>,
+    pub system_prompt: Option<String>,
+    pub prompt: Option<String>,
+    pub response: Option<String>,
+    pub request_id: Option<String>,
+    pub cached: bool, // Answered from the response cache
+    pub shared: bool, // Answered by an identical request that was already in flight
+    pub callback_status: Option<String>, // Webhook delivery for async jobs: pending, delivered or failed
+    pub callback_attempts: Option<i64>,
+}
+
+fn add_missing_columns(conn: &Connection) -> Result<()> {
+    let mut stmt = conn.prepare("PRAGMA table_info(LOGS)")?;
+    let existing = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<_>>>()?;
+    for (name, column_type) in ADDED_COLUMNS {
+        if !existing.iter().any(|c| c.eq_ignore_ascii_case(name)) {
+            conn.execute(&format!("ALTER TABLE LOGS ADD COLUMN {} {}", name, column_type), [])?;
+        }
+    }
+    Ok(())
+}
+
+// Escape LIKE wildcards so text search matches literally
+fn escape_like(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
 
 pub struct DbClient {
     db_path: String,
+    caller: Option<String>, // Who made the request being logged, recorded on every row
+    request_id: Option<String>, // ID of the /api/chat call being logged, also sent back in the x-request-id header
 }
 
+// Columns added to LOGS after the original schema, created on startup for existing databases
+const ADDED_COLUMNS: &[(&str, &str)] = &[
+    ("token_id", "INTEGER"),
+    ("caller", "TEXT"),
+    ("success", "INTEGER"),
+    ("request_id", "TEXT"),
+    ("cached", "INTEGER"),
+    ("shared", "INTEGER"),
+    ("callback_status", "TEXT"),
+    ("callback_attempts", "INTEGER"),
+];
+
 impl DbClient {
     // new now takes the path and just stores it. It also ensures the table exists.
     pub fn new(db_path: &str) -> Result<Self> {
@@ -21,7 +97,18 @@ impl DbClient {
             )",
             [],
         )?;
-        Ok(Self { db_path: db_path.to_string() })
+        add_missing_columns(&conn)?;
+        Ok(Self { db_path: db_path.to_string(), caller: None, request_id: None })
+    }
+
+    pub fn with_caller(mut self, caller: Option<&str>) -> Self {
+        self.caller = caller.map(str::trim).filter(|c| !c.is_empty()).map(str::to_string);
+        self
+    }
+
+    pub fn with_request_id(mut self, request_id: &str) -> Self {
+        self.request_id = Some(request_id.to_string());
+        self
     }
 
     // insert_log now opens its own connection
@@ -30,32 +117,267 @@ impl DbClient {
         system_prompt: &str,
         prompt: &str,
         response: &str,
-        token: &str,
-        token_type: &str,
+        token: &db_client::Token,
+        success: bool,
     ) -> Result<()> {
         // Try to open the connection
         let conn = match Connection::open(&self.db_path) {
             Ok(c) => c,
             Err(e) => {
-                eprintln!("CRITICAL: Failed to OPEN log database connection at '{}': {}", self.db_path, e);
+                error!(db_path = %self.db_path, error = %e, "Failed to open log database connection");
                 return Err(e); // Propagate the error
             }
         };
 
-        let now = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+        let now = Local::now().format(TIME_FORMAT).to_string();
+        // Never write the real key into LOGS, only enough of it to tell tokens apart
+        let masked_token = crypto::mask_token(&token.token);
 
         // Try to execute the insert statement
         match conn.execute(
-            "INSERT INTO LOGS (system_prompt, prompt, response, token, token_type, time)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
-            params![system_prompt, prompt, response, token, token_type, now],
+            "INSERT INTO LOGS (system_prompt, prompt, response, token, token_type, time, token_id, caller, success, request_id)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![system_prompt, prompt, response, masked_token, token.token_type, now, token.id, self.caller, success, self.request_id],
         ) {
             Ok(_) => Ok(()), // Success
             Err(e) => {
-                eprintln!("CRITICAL: Failed to EXECUTE insert into LOGS table: {}", e);
+                error!(error = %e, "Failed to insert into LOGS table");
                 Err(e) // Propagate the error
             }
         }
         // Connection is dropped here automatically
     }
+
+    // Record a request answered from the response cache, without using any token
+    pub fn insert_cached_log(&self, system_prompt: &str, prompt: &str, response: &str, token_type: &str) -> Result<()> {
+        let conn = Connection::open(&self.db_path)?;
+        let now = Local::now().format(TIME_FORMAT).to_string();
+        conn.execute(
+            "INSERT INTO LOGS (system_prompt, prompt, response, token, token_type, time, caller, success, request_id, cached)
+             VALUES (?1, ?2, ?3, '', ?4, ?5, ?6, 1, ?7, 1)",
+            params![system_prompt, prompt, response, token_type, now, self.caller, self.request_id],
+        )?;
+        Ok(())
+    }
+
+    // Record a request that received the result of an identical in-flight request instead of calling a provider.
+    // token_type is empty when that request failed.
+    pub fn insert_shared_log(&self, system_prompt: &str, prompt: &str, response: &str, token_type: &str, success: bool) -> Result<()> {
+        let conn = Connection::open(&self.db_path)?;
+        let now = Local::now().format(TIME_FORMAT).to_string();
+        conn.execute(
+            "INSERT INTO LOGS (system_prompt, prompt, response, token, token_type, time, caller, success, request_id, shared)
+             VALUES (?1, ?2, ?3, '', ?4, ?5, ?6, ?7, ?8, 1)",
+            params![system_prompt, prompt, response, token_type, now, self.caller, success, self.request_id],
+        )?;
+        Ok(())
+    }
+
+    /// Record the webhook delivery state of an async job on the LOGS rows written for it.
+    pub fn set_callback_status(&self, request_id: &str, status: &str, attempts: u32) -> Result<usize> {
+        let conn = Connection::open(&self.db_path)?;
+        conn.execute(
+            "UPDATE LOGS SET callback_status = ?1, callback_attempts = ?2 WHERE request_id = ?3",
+            params![status, attempts, request_id],
+        )
+    }
+
+    /// Search LOGS, newest first. Returns one page of matching rows and the total number of matches.
+    pub fn query_logs(&self, filter: &LogFilter) -> Result<(Vec<LogEntry>, i64)> {
+        let conn = Connection::open(&self.db_path)?;
+
+        let mut conditions: Vec<&str> = Vec::new();
+        let mut values: Vec<Value> = Vec::new();
+        if let Some(from) = &filter.from {
+            conditions.push("time >= ?");
+            values.push(from.clone().into());
+        }
+        if let Some(to) = &filter.to {
+            conditions.push("time <= ?");
+            values.push(to.clone().into());
+        }
+        if let Some(token_type) = &filter.token_type {
+            conditions.push("token_type = ?");
+            values.push(token_type.clone().into());
+        }
+        if let Some(token_id) = filter.token_id {
+            conditions.push("token_id = ?");
+            values.push(token_id.into());
+        }
+        if let Some(caller) = &filter.caller {
+            conditions.push("caller = ?");
+            values.push(caller.clone().into());
+        }
+        if let Some(request_id) = &filter.request_id {
+            conditions.push("request_id = ?");
+            values.push(request_id.clone().into());
+        }
+        if let Some(success) = filter.success {
+            conditions.push("success = ?");
+            values.push((success as i64).into());
+        }
+        if let Some(text) = &filter.text {
+            conditions.push("(prompt LIKE ? ESCAPE '\\' OR system_prompt LIKE ? ESCAPE '\\' OR response LIKE ? ESCAPE '\\')");
+            let pattern = format!("%{}%", escape_like(text));
+            for _ in 0..3 {
+                values.push(pattern.clone().into());
+            }
+        }
+
+        let where_clause = if conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", conditions.join(" AND "))
+        };
+
+        let total: i64 = conn.query_row(
+            &format!("SELECT COUNT(*) FROM LOGS {}", where_clause),
+            params_from_iter(values.iter()),
+            |row| row.get(0),
+        )?;
+
+        values.push((filter.limit as i64).into());
+        values.push((filter.offset as i64).into());
+        let mut stmt = conn.prepare(&format!(
+            "SELECT id, time, token_type, token_id, token, caller, success, system_prompt, prompt, response, request_id, cached,
+                callback_status, callback_attempts, shared
+             FROM LOGS {} ORDER BY id DESC LIMIT ? OFFSET ?",
+            where_clause
+        ))?;
+        let rows = stmt.query_map(params_from_iter(values.iter()), |row| {
+            Ok(LogEntry {
+                id: row.get(0)?,
+                time: row.get(1)?,
+                token_type: row.get(2)?,
+                token_id: row.get(3)?,
+                token: row.get(4)?,
+                caller: row.get(5)?,
+                success: row.get(6)?,
+                system_prompt: row.get(7)?,
+                prompt: row.get(8)?,
+                response: row.get(9)?,
+                request_id: row.get(10)?,
+                cached: row.get::<_, Option<bool>>(11)?.unwrap_or(false),
+                callback_status: row.get(12)?,
+                callback_attempts: row.get(13)?,
+                shared: row.get::<_, Option<bool>>(14)?.unwrap_or(false),
+            })
+        })?;
+
+        Ok((rows.collect::<Result<Vec<_>>>()?, total))
+    }
+
+    /// Most recent failure per token_id logged within the last `hours`, as token_id -> (time, error).
+    pub fn recent_errors_by_token(&self, hours: i64) -> Result<HashMap<i64, (String, String)>> {
+        let conn = Connection::open(&self.db_path)?;
+        let cutoff = (Local::now() - ChronoDuration::hours(hours)).format(TIME_FORMAT).to_string();
+        let mut stmt = conn.prepare(
+            "SELECT token_id, time, response FROM LOGS
+            WHERE id IN (
+                SELECT MAX(id) FROM LOGS
+                WHERE success = 0 AND token_id IS NOT NULL AND time >= ?1
+                GROUP BY token_id
+            )",
+        )?;
+        let rows = stmt.query_map(params![cutoff], |row| {
+            Ok((row.get::<_, i64>(0)?, (row.get(1)?, row.get(2)?)))
+        })?;
+        rows.collect()
+    }
+
+    /// Number of failed attempts per token_id logged within the last `hours`.
+    pub fn failure_counts_by_token(&self, hours: i64) -> Result<HashMap<i64, i64>> {
+        let conn = Connection::open(&self.db_path)?;
+        let cutoff = (Local::now() - ChronoDuration::hours(hours)).format(TIME_FORMAT).to_string();
+        let mut stmt = conn.prepare(
+            "SELECT token_id, COUNT(*) FROM LOGS
+            WHERE success = 0 AND token_id IS NOT NULL AND time >= ?1
+            GROUP BY token_id",
+        )?;
+        let rows = stmt.query_map(params![cutoff], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect()
+    }
+
+    /// Delete LOGS rows older than `retention_days` and/or beyond the newest `max_rows`.
+    /// Returns the number of rows deleted.
+    pub fn prune(&self, retention_days: Option<u64>, max_rows: Option<u64>) -> Result<usize> {
+        let conn = Connection::open(&self.db_path)?;
+        let mut deleted = 0;
+
+        if let Some(days) = retention_days {
+            // `time` is stored as local "YYYY-MM-DD HH:MM:SS", which sorts correctly as text
+            let cutoff = (Local::now() - ChronoDuration::days(days as i64)).format(TIME_FORMAT).to_string();
+            deleted += conn.execute("DELETE FROM LOGS WHERE time < ?1", params![cutoff])?;
+        }
+
+        if let Some(max_rows) = max_rows {
+            deleted += conn.execute(
+                "DELETE FROM LOGS WHERE id <= (SELECT id FROM LOGS ORDER BY id DESC LIMIT 1 OFFSET ?1)",
+                params![max_rows as i64],
+            )?;
+        }
+
+        Ok(deleted)
+    }
+
+    // Give the space freed by pruning back to the filesystem. Locks the whole database while it runs.
+    pub fn vacuum(&self) -> Result<()> {
+        let conn = Connection::open(&self.db_path)?;
+        conn.execute_batch("VACUUM")
+    }
+}
+
+/// Background task that enforces the LOGS retention settings from the config.
+/// Does nothing unless a retention age, row limit or VACUUM interval is configured.
+pub async fn run_retention_task(config: &'static Config) {
+    if config.log_retention_days.is_none() && config.log_max_rows.is_none() && config.log_vacuum_interval_hours.is_none() {
+        return;
+    }
+    info!(
+        retention_days = ?config.log_retention_days,
+        max_rows = ?config.log_max_rows,
+        vacuum_interval_hours = ?config.log_vacuum_interval_hours,
+        prune_interval_seconds = config.log_prune_interval_seconds,
+        "Log retention enabled"
+    );
+
+    let mut interval = tokio::time::interval(Duration::from_secs(config.log_prune_interval_seconds));
+    let mut last_vacuum = Instant::now();
+
+    loop {
+        interval.tick().await;
+
+        let vacuum_due = config.log_vacuum_interval_hours
+            .map(|hours| last_vacuum.elapsed() >= Duration::from_secs(hours * 3600))
+            .unwrap_or(false);
+
+        // SQLite calls block, so keep them off the async worker threads
+        let result = tokio::task::spawn_blocking(move || -> Result<(usize, bool)> {
+            let client = DbClient::new(&config.log_db_path)?;
+            let deleted = client.prune(config.log_retention_days, config.log_max_rows)?;
+            if vacuum_due {
+                client.vacuum()?;
+            }
+            Ok((deleted, vacuum_due))
+        }).await;
+
+        match result {
+            Ok(Ok((deleted, vacuumed))) => {
+                if deleted > 0 {
+                    info!(deleted, "Pruned old rows from LOGS");
+                }
+                if vacuumed {
+                    info!(db_path = %config.log_db_path, "Vacuumed log database");
+                    last_vacuum = Instant::now();
+                }
+            }
+            Ok(Err(e)) => warn!(error = %e, "Log pruning failed"),
+            Err(e) => warn!(error = %e, "Log pruning task panicked"),
+        }
+    }
+}
+
+// Convenience for request handlers: a log client for the configured LOGS database
+pub fn open_default() -> Result<DbClient> {
+    DbClient::new(&config::get().log_db_path)
 }
@@ -1,98 +1,198 @@
 mod db_client;
 mod api_client;
 mod log_client;
+mod crypto;
+mod config;
+mod admin;
+mod metrics;
+mod health;
+mod cache;
+mod semantic_cache;
+mod singleflight;
+mod jobs;
+mod webhooks;
+mod batch;
+mod token_queue;
+mod selection;
+mod providers;
+mod generation;
 
 use axum::{
     extract::{Json, Query, State},
+    http::HeaderMap,
     routing::{get, post},
     Router,
 };
 use serde::{Deserialize, Serialize};
 use std::{net::SocketAddr, sync::Arc, fs}; // Added fs and io
-use api_client::{LLMClient, GeminiClient, OpenRouterClient, LLMError}; // Added OpenRouterClient here
-use regex::Regex; // Import Regex
+use tracing::{debug, error, info, info_span, warn, Instrument};
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize, Clone)]
 struct ChatRequest {
+    #[serde(default)] // Batches send `prompts` instead and fill this in per item; still required, see handle_chat_request
     prompt: String,
     system_prompt: String,
     llm: Option<String>, // Comma-separated list of LLMs, e.g. "gemini,openrouter"
+    #[serde(skip_serializing)] // Never persisted with queued jobs
     access_token: Option<String>, // Added access token field
+    caller: Option<String>, // Optional identifier of the calling service or user, recorded in LOGS
+    model: Option<String>, // Optional model overrides per token type, see providers::ModelOverrides
+    cache: Option<bool>, // Set to false to skip the response cache lookup and force a fresh answer
+    strategy: Option<String>, // "ordered" (default) tries llm types in the given order, "any" treats them equally
+    selection: Option<String>, // Token selection strategy for this request, see selection::NAMES
+    temperature: Option<f64>, // Generation parameters, see generation.rs
+    max_tokens: Option<u32>,
+    top_p: Option<f64>,
+    stop: Option<generation::Stop>,
+    response_schema: Option<serde_json::Value>, // JSON Schema for structured output, returned parsed in `json`
+}
+
+impl ChatRequest {
+    fn generation_params(&self) -> generation::GenerationParams {
+        generation::GenerationParams {
+            temperature: self.temperature,
+            max_tokens: self.max_tokens,
+            top_p: self.top_p,
+            stop: self.stop.clone().map(generation::Stop::into_vec),
+            response_schema: self.response_schema.clone().map(generation::response_schema_from),
+        }
+    }
 }
 
 // Define the response structure
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 struct ChatResponse {
     content: String,
     token_type: String,
+    cached: bool, // True if served from the response cache without calling a provider
+    #[serde(skip_serializing_if = "Option::is_none")]
+    json: Option<serde_json::Value>, // The parsed answer, when the request had a response_schema
 }
 
 // Error response
-#[derive(Serialize)]
-struct ErrorResponse {
+#[derive(Serialize, Clone)]
+pub struct ErrorResponse {
     error: String,
 }
 
-// Empty app state since we create clients per-request
-struct AppState {}
+// Clients are still created per-request; the state only tracks requests in flight
+pub struct AppState {
+    in_flight: singleflight::SingleFlight<Result<ChatResponse, ErrorResponse>>,
+    job_notify: tokio::sync::Notify, // Wakes an idle job worker when a job is submitted
+}
+
+impl AppState {
+    fn new() -> Self {
+        Self {
+            in_flight: singleflight::SingleFlight::new(),
+            job_notify: tokio::sync::Notify::new(),
+        }
+    }
+}
+
+// Header carrying the request ID, accepted from the caller and always set on the response
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+type ChatResult = ([(&'static str, String); 1], Json<Result<ChatResponse, ErrorResponse>>);
+
+// Reuse a sane caller-supplied request ID (e.g. from a gateway), otherwise generate one
+fn request_id_from(headers: &HeaderMap) -> String {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|id| !id.is_empty() && id.len() <= 128)
+        .filter(|id| id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')))
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string())
+}
+
+// Runs a chat request inside a span carrying its request ID, so every diagnostic it emits is correlated
+async fn handle_chat_with_request_id(
+    state: Arc<AppState>,
+    headers: HeaderMap,
+    request: ChatRequest,
+) -> ChatResult {
+    let request_id = request_id_from(&headers);
+    let span = info_span!("chat", request_id = %request_id);
+    let response = match check_access_token(request.access_token.as_deref()) {
+        Ok(()) => handle_chat_request(state, request, &request_id).instrument(span).await,
+        Err(e) => Json(Err(e)),
+    };
+    ([(REQUEST_ID_HEADER, request_id)], response)
+}
 
 // Handler for POST requests
 async fn handle_post_chat(
     State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
     Json(request): Json<ChatRequest>,
-) -> Json<Result<ChatResponse, ErrorResponse>> {
-    handle_chat_request(state, request).await
+) -> ChatResult {
+    handle_chat_with_request_id(state, headers, request).await
 }
 
 // Handler for GET requests
 async fn handle_get_chat(
     State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
     Query(params): Query<ChatRequest>,
-) -> Json<Result<ChatResponse, ErrorResponse>> {
-    handle_chat_request(state, params).await
+) -> ChatResult {
+    handle_chat_with_request_id(state, headers, params).await
 }
 
-// Helper function to parse the token ID from the switch error message
-fn parse_token_id_from_switch_error(error_msg: &str) -> Option<i64> {
-    // Example error: "Token type switched to 'gemini' (ID: 5), requires different client. Last error: ..."
-    let re = Regex::new(r"\(ID: (\d+)\)").unwrap(); // Simple regex to find (ID: number)
-    re.captures(error_msg)
-        .and_then(|caps| caps.get(1))
-        .and_then(|m| m.as_str().parse::<i64>().ok())
+// Return a cached answer, recording the hit in LOGS
+fn cache_hit_response(
+    log_client: &log_client::DbClient,
+    request: &ChatRequest,
+    params: &generation::GenerationParams,
+    hit: cache::CachedResponse,
+) -> Json<Result<ChatResponse, ErrorResponse>> {
+    if let Err(log_err) = log_client.insert_cached_log(&request.system_prompt, &request.prompt, &hit.content, &hit.token_type) {
+        error!(error = %log_err, "Failed to log cache hit");
+    }
+    // The schema is part of the cache key, so the cached answer already passed it
+    let json = params.response_schema.as_ref().and_then(|schema| generation::parse_answer(&hit.content, schema).ok());
+    Json(Ok(ChatResponse { content: hit.content, token_type: hit.token_type, cached: true, json }))
 }
 
-// Common handler for both GET and POST
-async fn handle_chat_request(
-    _state: Arc<AppState>,
-    request: ChatRequest,
-) -> Json<Result<ChatResponse, ErrorResponse>> {
+// Check the server access token from access_token.txt, if one is configured
+fn check_access_token(provided: Option<&str>) -> Result<(), ErrorResponse> {
     let required_token = match fs::read_to_string("access_token.txt") {
         Ok(token) => token.trim().to_string(),
         Err(_) => "".to_string(), // Treat as empty if read error occurs (e.g., file not found)
     };
 
     if !required_token.is_empty() {
-        match &request.access_token {
+        match provided {
             Some(user_token) if user_token.trim() == required_token => {
                 // Token matches, proceed
-                 println!("Access token validated successfully.");
+                 debug!("Access token validated successfully");
             }
             _ => {
-                 println!("Invalid or missing access token provided in request.");
+                 warn!("Invalid or missing access token provided in request");
                 // Token doesn't match or is missing
-                return Json(Err(ErrorResponse {
+                return Err(ErrorResponse {
                     error: "Invalid or missing access token".to_string(),
-                }));
+                });
             }
         }
     } else {
-         println!("No access token required (access_token.txt is empty or unreadable).");
+         debug!("No access token required (access_token.txt is empty or unreadable)");
         // No token required, proceed
     }
+    Ok(())
+}
+
+// Common handler for both GET and POST, and for queued jobs. The access token must already be checked.
+async fn handle_chat_request(
+    state: Arc<AppState>,
+    request: ChatRequest,
+    request_id: &str,
+) -> Json<Result<ChatResponse, ErrorResponse>> {
 
     // Initialize log database client
-    let log_client = match log_client::DbClient::new("data.db") { // Ensure path is correct
-        Ok(client) => client,
+    let log_client = match log_client::open_default() {
+        Ok(client) => client.with_caller(request.caller.as_deref()).with_request_id(request_id),
         Err(e) => return Json(Err(ErrorResponse {
             error: format!("Log database connection error: {}", e)
         })),
@@ -107,126 +207,270 @@ async fn handle_chat_request(
     });
     
     let llm_conditions_slice = llm_conditions_vec.as_deref();
+    if request.prompt.is_empty() {
+        return Json(Err(ErrorResponse { error: "A prompt is required".to_string() }));
+    }
+    let Some(strategy) = db_client::LlmStrategy::parse(request.strategy.as_deref()) else {
+        return Json(Err(ErrorResponse {
+            error: format!("Unknown strategy {:?}, expected \"ordered\" or \"any\"", request.strategy.as_deref().unwrap_or_default()),
+        }));
+    };
+    let selection = match request.selection.as_deref() {
+        None => selection::default_strategy(),
+        Some(name) => match selection::by_name(name) {
+            Some(selection) => selection,
+            None => return Json(Err(ErrorResponse {
+                error: format!("Unknown selection {:?}, expected one of: {}", name, selection::NAMES.join(", ")),
+            })),
+        },
+    };
+    let token_query = db_client::TokenQuery { llms: llm_conditions_slice, strategy, selection };
+    let params = request.generation_params();
+    if let Err(e) = params.validate() {
+        return Json(Err(ErrorResponse { error: format!("Invalid generation parameters: {}", e) }));
+    }
+    let models = match providers::ModelOverrides::parse(request.model.as_deref(), llm_conditions_slice) {
+        Ok(models) => models,
+        Err(error) => return Json(Err(ErrorResponse { error })),
+    };
+
+    // Answer from the response cache before claiming a token, when enabled
+    let cache_key = cache::enabled().then(|| {
+        cache::key(&request.system_prompt, &request.prompt, llm_conditions_slice, request.model.as_deref(), &params)
+    });
+    if let (Some(key), true) = (&cache_key, request.cache != Some(false)) {
+        match cache::lookup(key) {
+            Ok(Some(hit)) => {
+                info!(token_type = %hit.token_type, "Serving response from cache");
+                return cache_hit_response(&log_client, &request, &params, hit);
+            }
+            Ok(None) => {}
+            Err(e) => warn!(error = %e, "Response cache lookup failed, calling provider"),
+        }
+    }
+
+    // Near-duplicate prompts: the embedding is computed once and reused to index the fresh answer
+    let mut semantic_entry: Option<(String, Vec<f32>)> = None;
+    if semantic_cache::enabled() {
+        let scope = semantic_cache::scope(&request.system_prompt, llm_conditions_slice, request.model.as_deref(), &params);
+        match semantic_cache::embed_prompt(&request.prompt).await {
+            Ok(embedding) => {
+                if request.cache != Some(false) {
+                    match semantic_cache::lookup(&scope, &embedding) {
+                        Ok(Some((hit, similarity))) => {
+                            info!(token_type = %hit.token_type, similarity, "Serving response from semantic cache");
+                            return cache_hit_response(&log_client, &request, &params, hit);
+                        }
+                        Ok(None) => {}
+                        Err(e) => warn!(error = %e, "Semantic cache lookup failed, calling provider"),
+                    }
+                }
+                semantic_entry = Some((scope, embedding));
+            }
+            Err(e) => warn!(error = %e, "Failed to embed prompt, skipping semantic cache"),
+        }
+    }
 
-    // Get the initial token
-    let mut current_token = match db_client::get_next_token_by_llms(llm_conditions_slice) {
+    // Identical requests already in flight share one upstream call; only the leader stores the answer.
+    // Unlike cached answers, a shared call also has to pick its token the way each joining request asked to.
+    let answer_key = cache_key.clone().unwrap_or_else(|| {
+        cache::key(&request.system_prompt, &request.prompt, llm_conditions_slice, request.model.as_deref(), &params)
+    });
+    let flight_key = format!("{}|{:?}|{}", answer_key, strategy, request.selection.as_deref().unwrap_or_default());
+    let (result, shared) = state.in_flight.run(flight_key, async {
+        let result = call_providers(&request, &params, &models, token_query, &log_client).await;
+        if let Ok(response) = &result {
+            if let Some(key) = &cache_key {
+                if let Err(e) = cache::store(key, &response.content, &response.token_type) {
+                    warn!(error = %e, "Failed to store response in cache");
+                }
+            }
+            if let Some((scope, embedding)) = &semantic_entry {
+                if let Err(e) = semantic_cache::store(scope, &request.prompt, embedding, &response.content, &response.token_type) {
+                    warn!(error = %e, "Failed to store response in semantic cache");
+                }
+            }
+        }
+        result
+    }).await;
+    if shared {
+        info!("Shared the result of an identical in-flight request");
+        // The leader's LOGS rows carry its own request_id and caller, this request needs one of its own
+        let (response, token_type, success) = match &result {
+            Ok(response) => (response.content.as_str(), response.token_type.as_str(), true),
+            Err(e) => (e.error.as_str(), "", false),
+        };
+        if let Err(log_err) = log_client.insert_shared_log(&request.system_prompt, &request.prompt, response, token_type, success) {
+            error!(error = %log_err, "Failed to log shared response");
+        }
+    }
+    Json(result)
+}
+
+// Claim a token and call providers, switching clients when the retry logic moves to another token type
+async fn call_providers(
+    request: &ChatRequest,
+    params: &generation::GenerationParams,
+    models: &providers::ModelOverrides,
+    token_query: db_client::TokenQuery<'_>,
+    log_client: &log_client::DbClient,
+) -> Result<ChatResponse, ErrorResponse> {
+    // Get the initial token, waiting in line if the pool is exhausted
+    let current_token = match token_queue::next_token(token_query).await {
         Ok(Some(token)) => token,
         Ok(None) => {
-            let error_msg = if let Some(conds) = llm_conditions_slice {
+            let error_msg = if let Some(conds) = token_query.llms {
                 format!("No available tokens matching conditions: {:?}", conds)
             } else {
                 "No available tokens".to_string()
             };
-            return Json(Err(ErrorResponse { error: error_msg }));
+            return Err(ErrorResponse { error: error_msg });
         }
-        Err(e) => return Json(Err(ErrorResponse {
+        Err(e) => return Err(ErrorResponse {
             error: format!("Database error getting initial token: {}", e)
-        })),
+        }),
     };
 
-    // Loop to handle potential client switches
-    loop {
-        let response_result = match current_token.token_type.as_str() {
-            "gemini" => {
-                println!("Using Gemini client with token ID: {}", current_token.id);
-                let client = GeminiClient::new(current_token.token.clone());
-                client.generate_response(&request.prompt, &request.system_prompt, current_token.id, &log_client, llm_conditions_slice).await
-            },
-            "openrouter" => {
-                 println!("Using OpenRouter client with token ID: {}", current_token.id);
-                // Default model, could be made configurable
-                let model = "deepseek/deepseek-chat".to_string(); // Example model
-                let client = OpenRouterClient::new(current_token.token.clone(), model);
-                client.generate_response(&request.prompt, &request.system_prompt, current_token.id, &log_client, llm_conditions_slice).await
-            },
-            unsupported_type => {
-                println!("Encountered unsupported token type: {}", unsupported_type);
-                let error_msg = format!("Unsupported token type '{}' for token ID {}", unsupported_type, current_token.id);
-                
-                if let Err(log_err) = log_client.insert_log(
-                    &request.system_prompt,
-                    &request.prompt,
-                    &error_msg,
-                    &current_token.token,
-                    &current_token.token_type,
-                ) {
-                    println!("Failed to log error: {}", log_err);
-                }
-                
-                Err(LLMError(error_msg))
-            }
-        };
+    // Retries may move on to tokens of other types, the answer says which one produced it
+    match api_client::generate_response(
+        &request.prompt,
+        &request.system_prompt,
+        models,
+        params,
+        current_token,
+        log_client,
+        token_query,
+    ).await {
+        Ok(answer) => Ok(ChatResponse {
+            content: answer.content,
+            token_type: answer.token.token_type,
+            cached: false,
+            json: answer.json,
+        }),
+        Err(e) => {
+            warn!(error = %e, "Request failed");
+            Err(ErrorResponse { error: e.to_string() })
+        }
+    }
+}
 
-        match response_result {
-            Ok(content) => {
-                // Successful response, break the loop and return
-                return Json(Ok(ChatResponse {
-                    content,
-                    token_type: current_token.token_type, // Return the type of the token that succeeded
-                }));
-            }
-            Err(e) => {
-                let error_string = e.to_string();
-                // Check if it's the specific error indicating a client switch is needed
-                if error_string.contains("requires different client") {
-                     println!("Detected token type switch requirement: {}", error_string);
-                    // Attempt to parse the new token ID from the error message
-                    if let Some(new_token_id) = parse_token_id_from_switch_error(&error_string) {
-                         println!("Attempting to switch to token ID: {}", new_token_id);
-                        // Fetch the details of the new token
-                        match db_client::get_token_by_id(new_token_id) {
-                            Ok(Some(new_token_details)) => {
-                                 println!("Successfully fetched details for new token ID: {}", new_token_id);
-                                current_token = new_token_details; // Update current_token
-                                continue; // Continue the loop to try with the new client/token
-                            }
-                            Ok(None) => {
-                                 println!("Failed to find details for switched token ID: {}", new_token_id);
-                                // If the new token ID isn't found, return an error
-                                return Json(Err(ErrorResponse {
-                                    error: format!("Failed to switch client: New token ID {} not found after error: {}", new_token_id, error_string)
-                                }));
-                            }
-                            Err(db_err) => {
-                                 println!("Database error fetching details for switched token ID {}: {}", new_token_id, db_err);
-                                // If there's a DB error fetching the new token, return an error
-                                return Json(Err(ErrorResponse {
-                                    error: format!("Database error fetching switched token ID {}: {}. Original error: {}", new_token_id, db_err, error_string)
-                                }));
-                            }
-                        }
-                    } else {
-                         println!("Failed to parse new token ID from switch error message: {}", error_string);
-                        // If we couldn't parse the ID from the error, return the original error
-                        return Json(Err(ErrorResponse { error: error_string }));
-                    }
-                } else {
-                    // Any other error (max retries, initial unsupported type, DB error during retry, etc.)
-                     println!("Non-switch error encountered: {}", error_string);
-                    return Json(Err(ErrorResponse { error: error_string }));
-                }
+// Diagnostics go to stdout as text or JSON lines, filtered by RUST_LOG (default "info")
+fn init_tracing(json: bool) {
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    if json {
+        builder.json().init();
+    } else {
+        builder.init();
+    }
+}
+
+// Maintenance commands, run as `safe-trigger <command> [args...]` instead of starting the server
+async fn run_command(command: &str, args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        "rekey" => {
+            // Encrypts plaintext tokens and callback secrets, and re-encrypts ones written under the old master key
+            let new_key = crypto::master_key().ok_or_else(|| format!(
+                "No master key configured, set {} or {} (or create {})",
+                crypto::MASTER_KEY_ENV, crypto::MASTER_KEY_FILE_ENV, crypto::DEFAULT_MASTER_KEY_FILE
+            ))?;
+            let old_key = crypto::old_master_key()?;
+            if old_key.is_none() {
+                println!("No old master key set ({} or {}), only plaintext or already current tokens can be converted.",
+                    crypto::OLD_MASTER_KEY_ENV, crypto::OLD_MASTER_KEY_FILE_ENV);
             }
+            let updated = db_client::reencrypt_tokens(old_key.as_slice(), new_key)?;
+            println!("Re-encrypted {} token(s) with the current master key.", updated);
+            let updated = jobs::reencrypt_callback_secrets(old_key.as_slice(), new_key)?;
+            println!("Re-encrypted {} job callback secret(s) with the current master key.", updated);
+            Ok(())
         }
-    } // End loop
+        "batch" => {
+            let [input, output] = args else {
+                return Err("Usage: safe-trigger batch <input.jsonl> <output.jsonl>".into());
+            };
+            batch::run_file(Arc::new(AppState::new()), input, output).await
+        }
+        unknown => Err(format!("Unknown command '{}'. Available commands: rekey, batch", unknown).into()),
+    }
+}
+
+// Prometheus scrape endpoint
+async fn handle_metrics() -> ([(&'static str, &'static str); 1], String) {
+    ([("content-type", "text/plain; version=0.0.4")], metrics::render())
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Initialize empty app state
-    let state = Arc::new(AppState {});
+    init_tracing(config::get().json_logs);
+    if let Err(e) = db_client::add_missing_token_columns() {
+        warn!(error = %e, "Failed to add new columns to the TOKENS table");
+    }
+    match db_client::install_token_type_check(&providers::token_types()) {
+        Ok(0) => {}
+        Ok(count) => warn!(count, known = ?providers::token_types(), "Some tokens have a token_type no provider handles, requests that get them fail"),
+        Err(e) => warn!(error = %e, "Failed to install the token_type check on the TOKENS table"),
+    }
+
+    crypto::check_master_key().map_err(|e| e.0)?;
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some((command, command_args)) = args.split_first() {
+        return run_command(command, command_args).await;
+    }
+
+    if crypto::master_key().is_some() {
+        match db_client::count_plaintext_tokens() {
+            Ok(0) => info!("Token encryption at rest is enabled"),
+            Ok(count) => warn!(count, "Some tokens in the token database are still plaintext, run `safe-trigger rekey` to encrypt them"),
+            Err(e) => warn!(error = %e, "Failed to check token encryption status"),
+        }
+    }
+
+    let config = config::get();
+    if config.separate_log_db() {
+        info!(log_db = %config.log_db_path, db = %config.db_path, "Writing request logs to a separate database from the token pool");
+    }
+    // Make sure the LOGS table exists before the pruning task starts
+    if let Err(e) = log_client::DbClient::new(&config.log_db_path) {
+        warn!(log_db = %config.log_db_path, error = %e, "Failed to open log database");
+    }
+    tokio::spawn(log_client::run_retention_task(config));
+
+    let state = Arc::new(AppState::new());
+
+    match jobs::requeue_interrupted_jobs() {
+        Ok(0) => {}
+        Ok(count) => info!(count, "Requeued jobs interrupted by the last shutdown"),
+        Err(e) => warn!(error = %e, "Failed to requeue interrupted jobs"),
+    }
+    for worker in 0..config.job_workers {
+        tokio::spawn(jobs::run_worker(state.clone(), worker));
+    }
+    tokio::spawn(jobs::run_cleanup_task());
+    webhooks::resume_pending_deliveries();
 
     // Create the router with both GET and POST endpoints
     let app = Router::new()
         .route("/api/chat", post(handle_post_chat))
         .route("/api/chat", get(handle_get_chat))
+        .route("/api/batch", post(batch::handle_batch))
+        .route("/api/jobs", post(jobs::handle_submit_job))
+        .route("/api/jobs/:id", get(jobs::handle_get_job))
+        .route("/admin/logs", get(admin::handle_get_logs))
+        .route("/admin/tokens", get(admin::handle_get_tokens))
+        .route("/admin/providers", get(admin::handle_get_providers))
+        .route("/metrics", get(handle_metrics))
+        .route("/healthz", get(health::handle_healthz))
+        .route("/readyz", get(health::handle_readyz))
         .with_state(state);
 
     // Set up the server address
-    // let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
-    let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
-    println!("Server listening on {}", addr);
-    println!("POST to /api/chat with JSON body {{ \"prompt\": \"...\", \"system_prompt\": \"...\", \"llm\": \"optional,comma,separated\", \"access_token\": \"...\" }}");
-    println!("GET from /api/chat?prompt=...&system_prompt=...&llm=optional,comma,separated&access_token=...");
+    let addr = SocketAddr::from(([0, 0, 0, 0], config.port));
+    info!("Server listening on {}", addr);
+    info!("POST to /api/chat with JSON body {{ \"prompt\": \"...\", \"system_prompt\": \"...\", \"llm\": \"optional,comma,separated\", \"access_token\": \"...\" }}");
+    info!("GET from /api/chat?prompt=...&system_prompt=...&llm=optional,comma,separated&access_token=...");
 
     // Start the server
     axum::Server::bind(&addr)
@@ -0,0 +1,94 @@
+use prometheus::{
+    Encoder, HistogramOpts, HistogramTimer, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::sync::OnceLock;
+use tracing::warn;
+
+use crate::db_client;
+
+// All Prometheus metrics exposed on /metrics
+struct Metrics {
+    registry: Registry,
+    provider_requests: IntCounterVec,
+    provider_latency: HistogramVec,
+    retries: IntCounterVec,
+    tokens: IntGaugeVec,
+    db_latency: HistogramVec,
+}
+
+fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(|| {
+        let registry = Registry::new();
+
+        let provider_requests = IntCounterVec::new(
+            Opts::new("safe_trigger_provider_requests_total", "Upstream LLM API calls by provider and outcome"),
+            &["provider", "outcome"],
+        ).unwrap();
+        let provider_latency = HistogramVec::new(
+            HistogramOpts::new("safe_trigger_provider_request_duration_seconds", "Upstream LLM API call latency by provider and outcome")
+                .buckets(vec![0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 40.0, 80.0, 160.0]),
+            &["provider", "outcome"],
+        ).unwrap();
+        let retries = IntCounterVec::new(
+            Opts::new("safe_trigger_retries_total", "Failed attempts handled by the retry logic, by provider of the failing token"),
+            &["provider"],
+        ).unwrap();
+        let tokens = IntGaugeVec::new(
+            Opts::new("safe_trigger_tokens", "Tokens in the pool by token_type and state (available, cooling_down, in_trouble)"),
+            &["token_type", "state"],
+        ).unwrap();
+        let db_latency = HistogramVec::new(
+            HistogramOpts::new("safe_trigger_db_operation_duration_seconds", "SQLite token pool operation latency")
+                .buckets(vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0]),
+            &["operation"],
+        ).unwrap();
+
+        registry.register(Box::new(provider_requests.clone())).unwrap();
+        registry.register(Box::new(provider_latency.clone())).unwrap();
+        registry.register(Box::new(retries.clone())).unwrap();
+        registry.register(Box::new(tokens.clone())).unwrap();
+        registry.register(Box::new(db_latency.clone())).unwrap();
+
+        Metrics { registry, provider_requests, provider_latency, retries, tokens, db_latency }
+    })
+}
+
+/// Record one upstream API call.
+pub fn record_provider_request(provider: &str, success: bool, seconds: f64) {
+    let outcome = if success { "success" } else { "error" };
+    let m = metrics();
+    m.provider_requests.with_label_values(&[provider, outcome]).inc();
+    m.provider_latency.with_label_values(&[provider, outcome]).observe(seconds);
+}
+
+pub fn record_retry(provider: &str) {
+    metrics().retries.with_label_values(&[provider]).inc();
+}
+
+/// Times a database operation until the returned guard is dropped.
+pub fn db_timer(operation: &str) -> HistogramTimer {
+    metrics().db_latency.with_label_values(&[operation]).start_timer()
+}
+
+/// Render all metrics in the Prometheus text format. Token pool gauges are refreshed from the database on every scrape.
+pub fn render() -> String {
+    let m = metrics();
+
+    match db_client::count_tokens_by_state() {
+        Ok(counts) => {
+            // Reset so token types that disappeared from the pool don't keep their last value
+            m.tokens.reset();
+            for (token_type, state, count) in counts {
+                m.tokens.with_label_values(&[&token_type, &state]).set(count);
+            }
+        }
+        Err(e) => warn!(error = %e, "Failed to count tokens for metrics"),
+    }
+
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&m.registry.gather(), &mut buffer) {
+        warn!(error = %e, "Failed to encode metrics");
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
@@ -0,0 +1,145 @@
+use serde_json::{json, Value};
+use std::time::{Duration, Instant};
+
+use crate::api_client::{self, AttemptResult, LLMClient, LLMError};
+use crate::config;
+use crate::db_client::Token;
+use crate::generation::GenerationParams;
+use crate::metrics;
+
+use super::{Capabilities, Provider};
+
+pub const DEFAULT_MODEL: &str = "claude-3-5-haiku-latest";
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+const ANTHROPIC_MAX_TOKENS: u32 = 4096; // Required by the Messages API, used when the request sets no max_tokens
+const ANTHROPIC_MAX_TEMPERATURE: f64 = 1.0; // Narrower than the 0-2 accepted on requests
+const ANTHROPIC_OVERLOADED: u16 = 529;
+const OVERLOAD_DELAY_SECONDS: u64 = 5; // Used when an overloaded response has no retry-after header
+const MAX_OVERLOAD_DELAY_SECONDS: u64 = 30; // Longer retry-after values are cut to this, the request is waiting
+
+pub const PROVIDER: Provider = Provider {
+    token_type: "anthropic",
+    capabilities: Capabilities { vision: true, tools: true, json_schema: false },
+    cooldown: true,
+    create,
+};
+
+fn create(token: &Token, model_override: Option<&str>) -> Result<Box<dyn LLMClient>, LLMError> {
+    Ok(Box::new(AnthropicClient::new(
+        token.token.clone(),
+        model_override.unwrap_or(DEFAULT_MODEL).to_string(),
+    )))
+}
+
+#[derive(Clone)]
+pub struct AnthropicClient {
+    api_key: String,
+    model: String,
+}
+
+impl AnthropicClient {
+    pub fn new(api_key: String, model: String) -> Self {
+        Self { api_key, model }
+    }
+}
+
+#[async_trait::async_trait]
+impl LLMClient for AnthropicClient {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn attempt_generate(&self, prompt: &str, system_prompt: &str, params: &GenerationParams) -> AttemptResult {
+        let mut request_body = json!({
+            "model": self.model,
+            "max_tokens": params.max_tokens.unwrap_or(ANTHROPIC_MAX_TOKENS),
+            "messages": [
+                {
+                    "role": "user",
+                    "content": prompt
+                }
+            ]
+        });
+        // No native JSON mode, so the schema is spelled out in the system prompt and the answer checked afterwards
+        let system_prompt = match &params.response_schema {
+            Some(schema) => format!(
+                "{}\n\nRespond only with a JSON value matching this JSON Schema, without any other text:\n{}",
+                system_prompt, schema
+            ),
+            None => system_prompt.to_string(),
+        };
+        // The system prompt is a top-level field rather than a message, and may not be empty
+        let system_prompt = system_prompt.trim();
+        if !system_prompt.is_empty() {
+            request_body["system"] = json!(system_prompt);
+        }
+        // Clamped rather than sent as is: the 400 it would cause would put a healthy key in trouble
+        if let Some(temperature) = params.temperature {
+            request_body["temperature"] = json!(temperature.min(ANTHROPIC_MAX_TEMPERATURE));
+        }
+        if let Some(top_p) = params.top_p {
+            request_body["top_p"] = json!(top_p);
+        }
+        if let Some(stop) = &params.stop {
+            request_body["stop_sequences"] = json!(stop);
+        }
+
+        let api_url = format!("{}/messages", config::get().anthropic_base_url);
+        let client = reqwest::Client::new();
+        let started = Instant::now();
+        let mut overloaded_retry_after = None;
+
+        let mut unknown_model = false;
+        let result = async {
+            let response = client
+                .post(&api_url)
+                .header("Content-Type", "application/json")
+                .header("x-api-key", &self.api_key)
+                .header("anthropic-version", ANTHROPIC_VERSION)
+                .json(&request_body)
+                .send()
+                .await
+                .map_err(|e| LLMError(e.without_url().to_string()))?;
+
+            if response.status().is_success() {
+                let response_json: Value = response.json()
+                    .await
+                    .map_err(|e| LLMError(format!("Failed to parse JSON response: {}", e.without_url())))?;
+                // The answer is a list of content blocks, only text blocks are kept
+                let text: String = response_json
+                    .get("content")
+                    .and_then(|content| content.as_array())
+                    .map(|blocks| {
+                        blocks
+                            .iter()
+                            .filter(|block| block.get("type").and_then(|t| t.as_str()) == Some("text"))
+                            .filter_map(|block| block.get("text").and_then(|t| t.as_str()))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                if text.is_empty() {
+                    return Err(LLMError(format!("Failed to extract text from Anthropic response: {:?}", response_json)));
+                }
+                Ok(text)
+            } else {
+                let status = response.status();
+                if status.as_u16() == ANTHROPIC_OVERLOADED {
+                    let retry_after = response
+                        .headers()
+                        .get("retry-after")
+                        .and_then(|value| value.to_str().ok())
+                        .and_then(|value| value.parse::<u64>().ok())
+                        .unwrap_or(OVERLOAD_DELAY_SECONDS)
+                        .min(MAX_OVERLOAD_DELAY_SECONDS);
+                    overloaded_retry_after = Some(Duration::from_secs(retry_after));
+                }
+                let error_text = response.text().await.unwrap_or_else(|e| e.to_string());
+                unknown_model = api_client::is_unknown_model(status, &error_text);
+                Err(LLMError(format!("Error: {} - {}", status, error_text)))
+            }
+        }.await;
+        metrics::record_provider_request("anthropic", result.is_ok(), started.elapsed().as_secs_f64());
+
+        AttemptResult { result: result.map_err(|e| e.redacted(&[&self.api_key])), overloaded_retry_after, unknown_model }
+    }
+}
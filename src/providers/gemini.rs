@@ -0,0 +1,206 @@
+use serde_json::{json, Value};
+use std::time::Instant;
+
+use crate::api_client::{self, AttemptResult, LLMClient, LLMError};
+use crate::config;
+use crate::db_client::Token;
+use crate::generation::GenerationParams;
+use crate::metrics;
+
+use super::{Capabilities, Provider};
+
+pub const DEFAULT_MODEL: &str = "gemini-2.5-flash-preview-04-17";
+
+pub const PROVIDER: Provider = Provider {
+    token_type: "gemini",
+    capabilities: Capabilities { vision: true, tools: true, json_schema: true },
+    cooldown: true,
+    create,
+};
+
+fn create(token: &Token, model_override: Option<&str>) -> Result<Box<dyn LLMClient>, LLMError> {
+    Ok(Box::new(GeminiClient::new(
+        token.token.clone(),
+        model_override.unwrap_or(DEFAULT_MODEL).to_string(),
+    )))
+}
+
+#[derive(Clone)]
+pub struct GeminiClient {
+    api_key: String,
+    model: String,
+}
+
+impl GeminiClient {
+    pub fn new(api_key: String, model: String) -> Self {
+        Self { api_key, model }
+    }
+
+    /// Embed `text` with this client's model, which must be an embedding model such as `text-embedding-004`.
+    pub async fn embed(&self, text: &str) -> Result<Vec<f32>, LLMError> {
+        let api_url = format!("{}/models/{}:embedContent", config::get().gemini_base_url, self.model);
+        let request_body = json!({
+            "model": format!("models/{}", self.model),
+            "content": { "parts": [ { "text": text } ] }
+        });
+        let started = Instant::now();
+
+        let result = async {
+            let response = reqwest::Client::new()
+                .post(&api_url)
+                .header("Content-Type", "application/json")
+                .header("x-goog-api-key", &self.api_key)
+                .json(&request_body)
+                .send()
+                .await
+                .map_err(|e| LLMError(e.without_url().to_string()))?;
+
+            if !response.status().is_success() {
+                let status = response.status();
+                let error_text = response.text().await.unwrap_or_else(|e| e.to_string());
+                return Err(LLMError(format!("Error: {} - {}", status, error_text)));
+            }
+            let response_json: Value = response.json()
+                .await
+                .map_err(|e| LLMError(format!("Failed to parse JSON response: {}", e.without_url())))?;
+            response_json
+                .pointer("/embedding/values")
+                .and_then(|values| values.as_array())
+                .map(|values| values.iter().filter_map(|v| v.as_f64()).map(|v| v as f32).collect::<Vec<f32>>())
+                .filter(|values| !values.is_empty())
+                .ok_or_else(|| LLMError("Failed to extract embedding from Gemini response".to_string()))
+        }.await;
+        metrics::record_provider_request("gemini_embedding", result.is_ok(), started.elapsed().as_secs_f64());
+
+        result.map_err(|e| e.redacted(&[&self.api_key]))
+    }
+}
+
+#[async_trait::async_trait]
+impl LLMClient for GeminiClient {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn attempt_generate(&self, prompt: &str, system_prompt: &str, params: &GenerationParams) -> AttemptResult {
+        let model_id = &self.model;
+        let generate_content_api = "streamGenerateContent"; // Use generateContent for non-streaming
+
+        let mut request_body = json!({
+            "contents": [
+                {
+                    "role": "user",
+                    "parts": [ { "text": prompt } ]
+                }
+            ],
+            "systemInstruction": {
+                "parts": [ { "text": system_prompt } ]
+            },
+            "generationConfig": {
+                "responseMimeType": "text/plain"
+            }
+        });
+        let generation_config = &mut request_body["generationConfig"];
+        if let Some(temperature) = params.temperature {
+            generation_config["temperature"] = json!(temperature);
+        }
+        if let Some(max_tokens) = params.max_tokens {
+            generation_config["maxOutputTokens"] = json!(max_tokens);
+        }
+        if let Some(top_p) = params.top_p {
+            generation_config["topP"] = json!(top_p);
+        }
+        if let Some(stop) = &params.stop {
+            generation_config["stopSequences"] = json!(stop);
+        }
+        if let Some(schema) = &params.response_schema {
+            generation_config["responseMimeType"] = json!("application/json");
+            // responseJsonSchema takes full JSON Schema; responseSchema only an OpenAPI subset that rejects
+            // keywords like additionalProperties or $ref
+            generation_config["responseJsonSchema"] = schema.clone();
+        }
+
+        // The key goes in a header rather than the query string so it never shows up in URLs or reqwest errors
+        let api_url = format!(
+            "{}/models/{}:{}",
+            config::get().gemini_base_url, model_id, generate_content_api
+        );
+
+        let client = reqwest::Client::new();
+        let started = Instant::now();
+        
+        let mut unknown_model = false;
+        let result = async {
+            let response = client
+                .post(&api_url)
+                .header("Content-Type", "application/json")
+                .header("x-goog-api-key", &self.api_key)
+                .json(&request_body)
+                .send()
+                .await
+                .map_err(|e| LLMError(e.without_url().to_string()))?;
+
+            if response.status().is_success() {
+                let response_json: Value = response.json()
+                    .await
+                    .map_err(|e| LLMError(format!("Failed to parse JSON response: {}", e.without_url())))?;
+                // debug!("Gemini response: {:?}", response_json); // Debugging line
+
+                // Handle both array and object root responses
+                // Try array root
+                if let Some(array) = response_json.as_array() {
+                    let mut all_text = String::new();
+                    for obj in array {
+                        if let Some(candidates) = obj.get("candidates") {
+                            for candidate in candidates.as_array().unwrap_or(&vec![]) {
+                                if let Some(content) = candidate.get("content") {
+                                    if let Some(parts) = content.get("parts") {
+                                        for part in parts.as_array().unwrap_or(&vec![]) {
+                                            if let Some(text) = part.get("text") {
+                                                if let Some(text_str) = text.as_str() {
+                                                    all_text.push_str(text_str);
+                                                }
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    if !all_text.is_empty() {
+                        return Ok(all_text);
+                    }
+                }
+                // Try object root (original logic)
+                if let Some(candidates) = response_json.get("candidates") {
+                    let mut all_text = String::new();
+                    for candidate in candidates.as_array().unwrap_or(&vec![]) {
+                        if let Some(content) = candidate.get("content") {
+                            if let Some(parts) = content.get("parts") {
+                                for part in parts.as_array().unwrap_or(&vec![]) {
+                                    if let Some(text) = part.get("text") {
+                                        if let Some(text_str) = text.as_str() {
+                                            all_text.push_str(text_str);
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    if !all_text.is_empty() {
+                        return Ok(all_text);
+                    }
+                }
+                Err(LLMError(format!("Failed to extract text from Gemini response: {:?}", response_json)))
+            } else {
+                let status = response.status();
+                let error_text = response.text().await.unwrap_or_else(|e| e.to_string());
+                unknown_model = api_client::is_unknown_model(status, &error_text);
+                Err(LLMError(format!("Error: {} - {}", status, error_text)))
+            }
+        }.await;
+        metrics::record_provider_request("gemini", result.is_ok(), started.elapsed().as_secs_f64());
+
+        AttemptResult { result: result.map_err(|e| e.redacted(&[&self.api_key])), overloaded_retry_after: None, unknown_model }
+    }
+}
@@ -0,0 +1,101 @@
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::api_client::{LLMClient, LLMError};
+use crate::db_client::Token;
+
+mod anthropic;
+mod gemini;
+mod ollama;
+mod openai_compat;
+mod openrouter;
+
+pub use gemini::GeminiClient;
+
+// Provider registry: every supported token_type maps to a factory for its client. Adding a provider means
+// writing a module with a PROVIDER entry and listing it in PROVIDERS below; the retry loop in api_client,
+// the TOKENS insert check and the token availability rule in db_client pick it up from here.
+
+/// What the provider's API offers beyond plain text chat, for callers choosing between token types.
+#[derive(Serialize, Clone, Copy)]
+pub struct Capabilities {
+    pub vision: bool,
+    pub tools: bool,
+    pub json_schema: bool, // Constrains answers to a response_schema natively; others are only asked to in the prompt
+}
+
+/// Builds the client for one token row. The model override comes from the request.
+pub type Factory = fn(&Token, Option<&str>) -> Result<Box<dyn LLMClient>, LLMError>;
+
+pub struct Provider {
+    pub token_type: &'static str,
+    pub capabilities: Capabilities,
+    pub cooldown: bool, // Whether a handed-out token sits out delay_by_second; false for providers limited some other way
+    pub create: Factory,
+}
+
+const PROVIDERS: &[Provider] = &[
+    gemini::PROVIDER,
+    openrouter::PROVIDER,
+    anthropic::PROVIDER,
+    openai_compat::PROVIDER,
+    ollama::PROVIDER,
+];
+
+/// Every registered provider, in the order above.
+pub fn all() -> &'static [Provider] {
+    PROVIDERS
+}
+
+pub fn get(token_type: &str) -> Option<&'static Provider> {
+    PROVIDERS.iter().find(|provider| provider.token_type == token_type)
+}
+
+/// The token types TOKENS rows may use.
+pub fn token_types() -> Vec<&'static str> {
+    PROVIDERS.iter().map(|provider| provider.token_type).collect()
+}
+
+/// The request's `model`, as one model per token type. Model names mean nothing to other providers, so
+/// each entry is `token_type:model`, or a bare model when `llm` names a single type. Types without an
+/// entry use their provider's default, e.g. `model=gemini:gemini-2.0-flash` with `llm=gemini,openrouter`.
+#[derive(Default)]
+pub struct ModelOverrides(HashMap<String, String>);
+
+impl ModelOverrides {
+    pub fn parse(value: Option<&str>, llms: Option<&[&str]>) -> Result<Self, String> {
+        let mut models = HashMap::new();
+        for entry in value.unwrap_or_default().split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            // Model names may contain colons themselves, e.g. Ollama's "llama3:8b"
+            let (token_type, model) = match entry.split_once(':') {
+                Some((token_type, model)) if get(token_type).is_some() => (token_type, model.trim()),
+                _ => match llms {
+                    Some([token_type]) => (*token_type, entry),
+                    _ => return Err(format!(
+                        "model {:?} needs a token type, e.g. gemini:{}, unless llm names a single type",
+                        entry, entry
+                    )),
+                },
+            };
+            if model.is_empty() {
+                return Err(format!("model {:?} has no model name", entry));
+            }
+            if models.insert(token_type.to_string(), model.to_string()).is_some() {
+                return Err(format!("model names more than one model for {}", token_type));
+            }
+        }
+        Ok(Self(models))
+    }
+
+    pub fn for_type(&self, token_type: &str) -> Option<&str> {
+        self.0.get(token_type).map(String::as_str)
+    }
+}
+
+/// Client for `token`, failing for a type no provider handles or a row its provider can't use.
+pub fn client_for(token: &Token, models: &ModelOverrides) -> Result<Box<dyn LLMClient>, LLMError> {
+    let provider = get(&token.token_type).ok_or_else(|| {
+        LLMError(format!("Unsupported token type '{}' for token ID {}", token.token_type, token.id))
+    })?;
+    (provider.create)(token, models.for_type(&token.token_type))
+}
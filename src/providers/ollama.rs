@@ -0,0 +1,143 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Instant;
+
+use crate::api_client::{self, AttemptResult, LLMClient, LLMError};
+use crate::config;
+use crate::db_client::Token;
+use crate::generation::GenerationParams;
+use crate::metrics;
+
+use super::{Capabilities, Provider};
+
+pub const DEFAULT_URL: &str = "http://localhost:11434";
+
+// Vision and tools need a model that supports them, the API itself does
+pub const PROVIDER: Provider = Provider {
+    token_type: "ollama",
+    capabilities: Capabilities { vision: true, tools: true, json_schema: true },
+    cooldown: false,
+    create,
+};
+
+fn create(token: &Token, model_override: Option<&str>) -> Result<Box<dyn LLMClient>, LLMError> {
+    Ok(Box::new(OllamaClient::for_token(token, model_override)?))
+}
+
+/// A local Ollama server through its native /api/chat endpoint. No API key; instead of cooldowns, each
+/// model serves at most SAFE_TRIGGER_OLLAMA_CONCURRENCY requests at once and further requests wait their turn.
+#[derive(Clone)]
+pub struct OllamaClient {
+    base_url: String,
+    model: String,
+}
+
+// One fair semaphore per server and model, shared by every request in the process
+fn ollama_slots(base_url: &str, model: &str) -> Arc<tokio::sync::Semaphore> {
+    static SLOTS: OnceLock<Mutex<HashMap<String, Arc<tokio::sync::Semaphore>>>> = OnceLock::new();
+    SLOTS
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap()
+        .entry(format!("{} {}", base_url, model))
+        .or_insert_with(|| Arc::new(tokio::sync::Semaphore::new(config::get().ollama_concurrency)))
+        .clone()
+}
+
+impl OllamaClient {
+    /// Client for an "ollama" token. base_url defaults to the local server; a model must come from the request or the row.
+    pub fn for_token(token: &Token, model_override: Option<&str>) -> Result<Self, LLMError> {
+        let base_url = token
+            .base_url
+            .as_deref()
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .unwrap_or(DEFAULT_URL)
+            .trim_end_matches('/');
+        let model = model_override
+            .or(token.model.as_deref())
+            .ok_or_else(|| LLMError(format!("No model in the request and token {} has no default model", token.id)))?;
+        Ok(Self {
+            base_url: base_url.to_string(),
+            model: model.to_string(),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl LLMClient for OllamaClient {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn attempt_generate(&self, prompt: &str, system_prompt: &str, params: &GenerationParams) -> AttemptResult {
+        let mut request_body = json!({
+            "model": self.model,
+            "messages": [
+                {
+                    "role": "system",
+                    "content": system_prompt
+                },
+                {
+                    "role": "user",
+                    "content": prompt
+                }
+            ],
+            "stream": false
+        });
+        // Sampling settings go in "options", under Ollama's own names
+        let options: serde_json::Map<String, Value> = [
+            ("temperature", params.temperature.map(|t| json!(t))),
+            ("num_predict", params.max_tokens.map(|n| json!(n))),
+            ("top_p", params.top_p.map(|p| json!(p))),
+            ("stop", params.stop.as_ref().map(|s| json!(s))),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some((name.to_string(), value?)))
+        .collect();
+        if !options.is_empty() {
+            request_body["options"] = Value::Object(options);
+        }
+        if let Some(schema) = &params.response_schema {
+            request_body["format"] = schema.clone();
+        }
+        let api_url = format!("{}/api/chat", self.base_url);
+
+        // Wait for a free slot on this model; the semaphore is never closed
+        let slots = ollama_slots(&self.base_url, &self.model);
+        let _slot = slots.acquire().await.expect("Ollama semaphore closed");
+        let started = Instant::now();
+
+        let mut unknown_model = false;
+        let result = async {
+            let response = reqwest::Client::new()
+                .post(&api_url)
+                .header("Content-Type", "application/json")
+                .json(&request_body)
+                .send()
+                .await
+                .map_err(|e| LLMError(e.without_url().to_string()))?;
+
+            if response.status().is_success() {
+                let response_json: Value = response.json()
+                    .await
+                    .map_err(|e| LLMError(format!("Failed to parse JSON response: {}", e.without_url())))?;
+                response_json
+                    .pointer("/message/content")
+                    .and_then(|content| content.as_str())
+                    .map(str::to_string)
+                    .ok_or_else(|| LLMError(format!("Failed to extract text from Ollama response: {:?}", response_json)))
+            } else {
+                let status = response.status();
+                let error_text = response.text().await.unwrap_or_else(|e| e.to_string());
+                unknown_model = api_client::is_unknown_model(status, &error_text);
+                Err(LLMError(format!("Error: {} - {}", status, error_text)))
+            }
+        }.await;
+        metrics::record_provider_request("ollama", result.is_ok(), started.elapsed().as_secs_f64());
+
+        // No key to scrub, but the server may still echo credential-like text back
+        AttemptResult { result: result.map_err(|e| e.redacted(&[])), overloaded_retry_after: None, unknown_model }
+    }
+}
@@ -0,0 +1,64 @@
+use crate::api_client::{chat_completion, AttemptResult, LLMClient, LLMError};
+use crate::db_client::Token;
+use crate::generation::GenerationParams;
+
+use super::{Capabilities, Provider};
+
+// Vision and tool calls depend on the server and model behind each row, so only the basics are promised.
+// Structured output is sent as response_format, which OpenAI, vLLM and llama.cpp all accept.
+pub const PROVIDER: Provider = Provider {
+    token_type: "openai_compat",
+    capabilities: Capabilities { vision: false, tools: false, json_schema: true },
+    cooldown: true,
+    create,
+};
+
+fn create(token: &Token, model_override: Option<&str>) -> Result<Box<dyn LLMClient>, LLMError> {
+    Ok(Box::new(OpenAICompatClient::for_token(token, model_override)?))
+}
+
+/// Any server speaking the OpenAI chat completions API (OpenAI, DeepSeek, Groq, vLLM, llama.cpp, ...).
+/// Each "openai_compat" token row carries its own base_url, and usually its own default model.
+#[derive(Clone)]
+pub struct OpenAICompatClient {
+    api_key: String,
+    api_url: String,
+    model: String,
+}
+
+impl OpenAICompatClient {
+    /// Client for an "openai_compat" token, failing if the row has no base_url or no model can be chosen.
+    pub fn for_token(token: &Token, model_override: Option<&str>) -> Result<Self, LLMError> {
+        let base_url = token
+            .base_url
+            .as_deref()
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .ok_or_else(|| LLMError(format!("Token {} has no base_url, which openai_compat tokens need", token.id)))?;
+        let model = model_override
+            .or(token.model.as_deref())
+            .ok_or_else(|| LLMError(format!("No model in the request and token {} has no default model", token.id)))?;
+        // Accept both ".../v1" and the full ".../v1/chat/completions"
+        let api_url = if base_url.ends_with("/chat/completions") {
+            base_url.to_string()
+        } else {
+            format!("{}/chat/completions", base_url.trim_end_matches('/'))
+        };
+        Ok(Self {
+            api_key: token.token.clone(),
+            api_url,
+            model: model.to_string(),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl LLMClient for OpenAICompatClient {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn attempt_generate(&self, prompt: &str, system_prompt: &str, params: &GenerationParams) -> AttemptResult {
+        chat_completion("openai_compat", &self.api_url, &self.api_key, &self.model, prompt, system_prompt, params).await
+    }
+}
@@ -0,0 +1,46 @@
+use crate::api_client::{chat_completion, AttemptResult, LLMClient, LLMError};
+use crate::config;
+use crate::db_client::Token;
+use crate::generation::GenerationParams;
+
+use super::{Capabilities, Provider};
+
+pub const DEFAULT_MODEL: &str = "deepseek/deepseek-chat";
+
+pub const PROVIDER: Provider = Provider {
+    token_type: "openrouter",
+    capabilities: Capabilities { vision: true, tools: true, json_schema: true },
+    cooldown: true,
+    create,
+};
+
+fn create(token: &Token, model_override: Option<&str>) -> Result<Box<dyn LLMClient>, LLMError> {
+    Ok(Box::new(OpenRouterClient::new(
+        token.token.clone(),
+        model_override.unwrap_or(DEFAULT_MODEL).to_string(),
+    )))
+}
+
+#[derive(Clone)]
+pub struct OpenRouterClient {
+    api_key: String,
+    model: String,
+}
+
+impl OpenRouterClient {
+    pub fn new(api_key: String, model: String) -> Self {
+        Self { api_key, model }
+    }
+}
+
+#[async_trait::async_trait]
+impl LLMClient for OpenRouterClient {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn attempt_generate(&self, prompt: &str, system_prompt: &str, params: &GenerationParams) -> AttemptResult {
+        let api_url = format!("{}/chat/completions", config::get().openrouter_base_url);
+        chat_completion("openrouter", &api_url, &self.api_key, &self.model, prompt, system_prompt, params).await
+    }
+}
@@ -0,0 +1,229 @@
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI64, Ordering};
+use tracing::warn;
+
+use crate::{config, log_client};
+
+// Token selection: get_next_token_by_llms finds the tokens that are off cooldown (narrowed to the most
+// preferred LLM type for the "ordered" strategy) and a SelectionStrategy picks which one to claim.
+// The deployment default comes from SAFE_TRIGGER_TOKEN_SELECTION, requests can override it with `selection`.
+
+// Window least_errors looks back over when counting failures
+const ERROR_WINDOW_HOURS: i64 = 1;
+// random_jitter pushes a claimed token's triggered_on forward by up to this fraction of its delay
+const JITTER_FRACTION: f64 = 0.1;
+
+/// A token that is off cooldown and may be claimed.
+pub struct Candidate {
+    pub id: i64,
+    pub triggered_on: Option<i64>,
+    pub delay_by_second: i64,
+    pub weight: f64,
+}
+
+pub trait SelectionStrategy: Send + Sync {
+    /// Index into `candidates` of the token to claim. `candidates` is never empty.
+    fn pick(&self, candidates: &[Candidate]) -> usize;
+
+    /// Extra seconds added to the claimed token's triggered_on, lengthening this cooldown.
+    fn cooldown_jitter(&self, _candidate: &Candidate) -> i64 {
+        0
+    }
+}
+
+// Least recently triggered first, never-used tokens before all others
+fn least_recently_used(candidates: &[Candidate]) -> usize {
+    (0..candidates.len())
+        .min_by_key(|&i| (candidates[i].triggered_on.unwrap_or(i64::MIN), candidates[i].id))
+        .unwrap_or(0)
+}
+
+/// The original behavior: the token that has been idle the longest.
+pub struct Lru;
+
+impl SelectionStrategy for Lru {
+    fn pick(&self, candidates: &[Candidate]) -> usize {
+        least_recently_used(candidates)
+    }
+}
+
+/// Random, proportional to TOKENS.weight. Tokens with weight 0 are only used when nothing else is free.
+pub struct Weighted;
+
+impl SelectionStrategy for Weighted {
+    fn pick(&self, candidates: &[Candidate]) -> usize {
+        let total: f64 = candidates.iter().map(|c| c.weight.max(0.0)).sum();
+        if total <= 0.0 {
+            return rand::thread_rng().gen_range(0..candidates.len());
+        }
+        let mut point = rand::thread_rng().gen_range(0.0..total);
+        for (i, candidate) in candidates.iter().enumerate() {
+            point -= candidate.weight.max(0.0);
+            if point < 0.0 {
+                return i;
+            }
+        }
+        candidates.len() - 1
+    }
+}
+
+/// Cycles through tokens by id, continuing after the last one handed out.
+pub struct RoundRobin;
+
+static LAST_ROUND_ROBIN_ID: AtomicI64 = AtomicI64::new(i64::MIN);
+
+// The candidate with the next id after `last`, wrapping around to the lowest id
+fn next_after(candidates: &[Candidate], last: i64) -> usize {
+    let next = |after: i64| (0..candidates.len()).filter(|&i| candidates[i].id > after).min_by_key(|&i| candidates[i].id);
+    next(last).or_else(|| next(i64::MIN)).unwrap_or(0)
+}
+
+impl SelectionStrategy for RoundRobin {
+    fn pick(&self, candidates: &[Candidate]) -> usize {
+        let picked = next_after(candidates, LAST_ROUND_ROBIN_ID.load(Ordering::Relaxed));
+        LAST_ROUND_ROBIN_ID.store(candidates[picked].id, Ordering::Relaxed);
+        picked
+    }
+}
+
+/// Fewest failures logged in the last hour, least recently used among equals.
+pub struct LeastErrors;
+
+impl SelectionStrategy for LeastErrors {
+    fn pick(&self, candidates: &[Candidate]) -> usize {
+        let failures = match log_client::open_default().and_then(|logs| logs.failure_counts_by_token(ERROR_WINDOW_HOURS)) {
+            Ok(failures) => failures,
+            Err(e) => {
+                warn!(error = %e, "Failed to count token failures, falling back to least recently used");
+                return least_recently_used(candidates);
+            }
+        };
+        fewest_failures(candidates, &failures)
+    }
+}
+
+// Fewest failures by token id, ties broken like least_recently_used
+fn fewest_failures(candidates: &[Candidate], failures: &HashMap<i64, i64>) -> usize {
+    (0..candidates.len())
+        .min_by_key(|&i| {
+            let candidate = &candidates[i];
+            (failures.get(&candidate.id).copied().unwrap_or(0), candidate.triggered_on.unwrap_or(i64::MIN), candidate.id)
+        })
+        .unwrap_or(0)
+}
+
+/// Uniformly random, with a small random extension of the cooldown so keys claimed together
+/// don't all come off cooldown at the same moment.
+pub struct RandomJitter;
+
+impl SelectionStrategy for RandomJitter {
+    fn pick(&self, candidates: &[Candidate]) -> usize {
+        rand::thread_rng().gen_range(0..candidates.len())
+    }
+
+    fn cooldown_jitter(&self, candidate: &Candidate) -> i64 {
+        let max_jitter = (candidate.delay_by_second as f64 * JITTER_FRACTION).round() as i64;
+        if max_jitter <= 0 {
+            return 0;
+        }
+        rand::thread_rng().gen_range(0..=max_jitter)
+    }
+}
+
+pub const NAMES: &[&str] = &["lru", "weighted", "round_robin", "least_errors", "random_jitter"];
+
+/// The strategy with the given name, as used by SAFE_TRIGGER_TOKEN_SELECTION and the `selection` option.
+pub fn by_name(name: &str) -> Option<&'static dyn SelectionStrategy> {
+    match name.trim() {
+        "lru" => Some(&Lru),
+        "weighted" => Some(&Weighted),
+        "round_robin" => Some(&RoundRobin),
+        "least_errors" => Some(&LeastErrors),
+        "random_jitter" => Some(&RandomJitter),
+        _ => None,
+    }
+}
+
+/// The deployment's default strategy, from SAFE_TRIGGER_TOKEN_SELECTION.
+pub fn default_strategy() -> &'static dyn SelectionStrategy {
+    by_name(&config::get().token_selection).unwrap_or(&Lru)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(id: i64, triggered_on: Option<i64>, weight: f64) -> Candidate {
+        Candidate { id, triggered_on, delay_by_second: 600, weight }
+    }
+
+    #[test]
+    fn lru_prefers_never_used_then_oldest() {
+        let candidates = [candidate(1, Some(300), 1.0), candidate(2, Some(100), 1.0), candidate(3, None, 1.0)];
+        assert_eq!(Lru.pick(&candidates), 2);
+        assert_eq!(Lru.pick(&candidates[..2]), 1);
+    }
+
+    #[test]
+    fn weighted_skips_zero_weight_while_others_are_free() {
+        let candidates = [candidate(1, None, 0.0), candidate(2, None, 1.0), candidate(3, None, 0.0), candidate(4, None, 3.0)];
+        let mut picks = [0; 4];
+        for _ in 0..2000 {
+            picks[Weighted.pick(&candidates)] += 1;
+        }
+        assert_eq!((picks[0], picks[2]), (0, 0));
+        // Roughly 1:3, with plenty of slack for randomness
+        assert!(picks[3] > picks[1] * 2, "picks: {:?}", picks);
+    }
+
+    #[test]
+    fn weighted_falls_back_to_zero_weight_tokens() {
+        let candidates = [candidate(1, None, 0.0), candidate(2, None, 0.0)];
+        for _ in 0..100 {
+            assert!(Weighted.pick(&candidates) < 2);
+        }
+    }
+
+    #[test]
+    fn round_robin_wraps_by_id() {
+        // Candidates don't arrive sorted by id
+        let candidates = [candidate(7, None, 1.0), candidate(2, None, 1.0), candidate(5, None, 1.0)];
+        assert_eq!(next_after(&candidates, i64::MIN), 1);
+        assert_eq!(next_after(&candidates, 2), 2);
+        assert_eq!(next_after(&candidates, 5), 0);
+        assert_eq!(next_after(&candidates, 7), 1);
+        // The last id handed out may no longer be a candidate
+        assert_eq!(next_after(&candidates, 3), 2);
+
+        // Through the shared last id, which may start anywhere in the cycle
+        let mut order = Vec::new();
+        for _ in 0..6 {
+            order.push(candidates[RoundRobin.pick(&candidates)].id);
+        }
+        let start = order.iter().position(|&id| id == 2).unwrap();
+        assert_eq!(order[start..start + 3], [2, 5, 7]);
+    }
+
+    #[test]
+    fn least_errors_breaks_ties_by_lru() {
+        let candidates = [candidate(1, Some(100), 1.0), candidate(2, Some(300), 1.0), candidate(3, Some(200), 1.0), candidate(4, None, 1.0)];
+        let failures = HashMap::from([(1, 2), (4, 1)]);
+        // 2 and 3 have no failures, 3 has been idle longer
+        assert_eq!(fewest_failures(&candidates, &failures), 2);
+        let failures = HashMap::from([(1, 2), (2, 1), (3, 1), (4, 1)]);
+        assert_eq!(fewest_failures(&candidates, &failures), 3);
+    }
+
+    #[test]
+    fn jitter_stays_within_a_tenth_of_the_delay() {
+        // delay_by_second is 600, so at most 60 seconds
+        let token = candidate(1, None, 1.0);
+        let jitters: Vec<i64> = (0..1000).map(|_| RandomJitter.cooldown_jitter(&token)).collect();
+        assert!(jitters.iter().all(|jitter| (0..=60).contains(jitter)));
+        assert!(jitters.iter().any(|&jitter| jitter > 0));
+        // Too short a delay to extend, and other strategies never do
+        assert_eq!(RandomJitter.cooldown_jitter(&Candidate { delay_by_second: 4, ..candidate(1, None, 1.0) }), 0);
+        assert_eq!(Lru.cooldown_jitter(&token), 0);
+    }
+}
@@ -0,0 +1,121 @@
+use chrono::Utc;
+use rusqlite::{Connection, Result, params};
+use sha2::{Digest, Sha256};
+
+use crate::api_client::LLMError;
+use crate::providers::GeminiClient;
+use crate::cache::CachedResponse;
+use crate::config;
+use crate::generation::GenerationParams;
+use crate::db_client;
+use crate::metrics;
+
+// Semantic cache: answers for prompts whose embedding is close enough to an earlier prompt's.
+// Only the prompt is matched fuzzily; system prompt, LLM list and model must be identical (the "scope").
+// The index is a plain SQLite table scanned with cosine similarity, kept in its own file next to data.db.
+
+/// True if SAFE_TRIGGER_SEMANTIC_CACHE_THRESHOLD is set.
+pub fn enabled() -> bool {
+    config::get().semantic_cache_threshold.is_some()
+}
+
+/// Everything except the prompt that must match exactly for a semantic hit.
+pub fn scope(system_prompt: &str, llms: Option<&[&str]>, model: Option<&str>, params: &GenerationParams) -> String {
+    let mut material = serde_json::json!([system_prompt, llms.map(|l| l.join(",")), model]);
+    if !params.is_empty() {
+        material.as_array_mut().unwrap().push(serde_json::json!(params));
+    }
+    format!("{:x}", Sha256::digest(material.to_string().as_bytes()))
+}
+
+/// Embed a prompt with a Gemini token borrowed from the pool (see db_client::peek_token_by_type).
+/// Only Gemini has an embedding client, so its keys are the only ones that can be used here.
+pub async fn embed_prompt(prompt: &str) -> Result<Vec<f32>, LLMError> {
+    let token = db_client::peek_token_by_type("gemini")
+        .map_err(|e| LLMError(format!("Database error getting embedding token: {}", e)))?
+        .ok_or_else(|| LLMError("No usable 'gemini' token for embeddings".to_string()))?;
+    GeminiClient::new(token.token, config::get().embedding_model.clone()).embed(prompt).await
+}
+
+fn open() -> Result<Connection> {
+    let conn = Connection::open(&config::get().semantic_cache_db_path)?;
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS SEMANTIC_CACHE (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            scope TEXT NOT NULL,
+            prompt TEXT NOT NULL,
+            embedding BLOB NOT NULL,
+            response TEXT NOT NULL,
+            token_type TEXT NOT NULL,
+            created_at INTEGER NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS idx_semantic_cache_scope ON SEMANTIC_CACHE (scope);",
+    )?;
+    Ok(conn)
+}
+
+// Embeddings are stored as little-endian f32s
+fn to_blob(embedding: &[f32]) -> Vec<u8> {
+    embedding.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+fn from_blob(blob: &[u8]) -> Vec<f32> {
+    blob.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect()
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0; // Different embedding models, never a match
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a: f32 = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b: f32 = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+/// Nearest cached answer in `scope` at or above the similarity threshold, with its similarity.
+pub fn lookup(scope: &str, embedding: &[f32]) -> Result<Option<(CachedResponse, f32)>> {
+    let config = config::get();
+    let Some(threshold) = config.semantic_cache_threshold else { return Ok(None) };
+    let _timer = metrics::db_timer("semantic_cache_lookup");
+    let conn = open()?;
+    // Entries expire with the exact cache TTL when one is configured
+    let cutoff = config.cache_ttl_seconds.map(|ttl| Utc::now().timestamp() - ttl as i64).unwrap_or(i64::MIN);
+
+    let mut stmt = conn.prepare(
+        "SELECT embedding, response, token_type FROM SEMANTIC_CACHE WHERE scope = ?1 AND created_at > ?2",
+    )?;
+    let mut rows = stmt.query(params![scope, cutoff])?;
+    let mut best: Option<(CachedResponse, f32)> = None;
+    while let Some(row) = rows.next()? {
+        let blob: Vec<u8> = row.get(0)?;
+        let similarity = cosine_similarity(embedding, &from_blob(&blob));
+        if similarity >= threshold && best.as_ref().is_none_or(|(_, s)| similarity > *s) {
+            best = Some((CachedResponse { content: row.get(1)?, token_type: row.get(2)? }, similarity));
+        }
+    }
+    Ok(best)
+}
+
+/// Add an answer to the index, dropping the oldest entries beyond the configured maximum.
+pub fn store(scope: &str, prompt: &str, embedding: &[f32], content: &str, token_type: &str) -> Result<()> {
+    let config = config::get();
+    if config.semantic_cache_threshold.is_none() {
+        return Ok(());
+    }
+    let _timer = metrics::db_timer("semantic_cache_store");
+    let conn = open()?;
+    conn.execute(
+        "INSERT INTO SEMANTIC_CACHE (scope, prompt, embedding, response, token_type, created_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![scope, prompt, to_blob(embedding), content, token_type, Utc::now().timestamp()],
+    )?;
+    conn.execute(
+        "DELETE FROM SEMANTIC_CACHE WHERE id <= (SELECT id FROM SEMANTIC_CACHE ORDER BY id DESC LIMIT 1 OFFSET ?1)",
+        params![config.semantic_cache_max_entries as i64],
+    )?;
+    Ok(())
+}
@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+
+/// Coalesces concurrent calls with the same key: the first caller (the leader) runs the work,
+/// callers arriving while it is in flight wait for and share its result. Nothing is kept once
+/// the leader finishes, so this is deduplication, not caching.
+pub struct SingleFlight<T> {
+    in_flight: Mutex<HashMap<String, broadcast::Sender<T>>>,
+}
+
+// Removes the leader's entry even if its future is dropped mid-flight (e.g. the client disconnected),
+// which closes the channel so waiters stop waiting and run the work themselves
+struct LeaderGuard<'a, T> {
+    flights: &'a SingleFlight<T>,
+    key: Option<String>, // None once the entry has been taken out normally
+}
+
+impl<T> LeaderGuard<'_, T> {
+    // Take the entry out under the lock, so late arrivals start a new flight instead of waiting on a finished one
+    fn finish(mut self) -> Option<broadcast::Sender<T>> {
+        let key = self.key.take()?;
+        self.flights.in_flight.lock().unwrap().remove(&key)
+    }
+}
+
+impl<T> Drop for LeaderGuard<'_, T> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.flights.in_flight.lock().unwrap().remove(&key);
+        }
+    }
+}
+
+impl<T: Clone> SingleFlight<T> {
+    pub fn new() -> Self {
+        Self { in_flight: Mutex::new(HashMap::new()) }
+    }
+
+    /// Run `work` unless an identical call is already in flight. Returns the result and whether it was
+    /// shared from another caller's call.
+    pub async fn run<F>(&self, key: String, work: F) -> (T, bool)
+    where
+        F: Future<Output = T>,
+    {
+        let waiter = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(&key) {
+                Some(sender) => Some(sender.subscribe()),
+                None => {
+                    in_flight.insert(key.clone(), broadcast::channel(1).0);
+                    None
+                }
+            }
+        };
+
+        if let Some(mut receiver) = waiter {
+            if let Ok(result) = receiver.recv().await {
+                return (result, true);
+            }
+            // The leader was cancelled before finishing, do the work ourselves without coalescing
+            return (work.await, false);
+        }
+
+        let guard = LeaderGuard { flights: self, key: Some(key) };
+        let result = work.await;
+        if let Some(sender) = guard.finish() {
+            let _ = sender.send(result.clone()); // No receivers is fine
+        }
+        (result, false)
+    }
+}
@@ -0,0 +1,126 @@
+use rusqlite::Result;
+use std::collections::VecDeque;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tracing::debug;
+
+use crate::config;
+use crate::db_client::{self, LlmStrategy, Token, TokenQuery};
+use crate::providers;
+
+// Fair hand-out of tokens when the pool is exhausted. Every request looking for a token joins one
+// process-wide list in arrival order, with the token types it accepts. Each type belongs to the oldest
+// waiter that accepts it: a later request may only claim types no earlier waiter is after, so a stream of
+// "gemini,openrouter" requests can't overtake a "gemini" request that is waiting for the next Gemini key.
+// A waiter sleeps until the soonest key it may claim has passed triggered_on + delay_by_second, or until
+// an earlier waiter leaves the list, which may hand it more types. An uncontended request joins, claims
+// and leaves without waiting.
+
+// Re-check the pool at least this often, tokens may be freed or added by other processes
+const MAX_SLEEP_SECONDS: u64 = 30;
+
+struct Waiter {
+    id: u64,
+    llms: Option<Vec<String>>, // None accepts every type
+}
+
+#[derive(Default)]
+struct Waiters {
+    next_id: u64,
+    queue: VecDeque<Waiter>, // Oldest first
+}
+
+fn waiters() -> &'static Mutex<Waiters> {
+    static WAITERS: OnceLock<Mutex<Waiters>> = OnceLock::new();
+    WAITERS.get_or_init(Mutex::default)
+}
+
+// Signalled whenever a waiter leaves, so later ones re-check what they may claim
+fn departures() -> &'static tokio::sync::Notify {
+    static DEPARTURES: OnceLock<tokio::sync::Notify> = OnceLock::new();
+    DEPARTURES.get_or_init(tokio::sync::Notify::new)
+}
+
+// A request's place in the list, given up when it gets a token, times out or is dropped with its request
+struct Place(u64);
+
+impl Place {
+    fn join(llms: Option<&[&str]>) -> Self {
+        let mut waiters = waiters().lock().unwrap();
+        let id = waiters.next_id;
+        waiters.next_id += 1;
+        let llms = llms.filter(|llms| !llms.is_empty()).map(|llms| llms.iter().map(|llm| llm.to_string()).collect());
+        waiters.queue.push_back(Waiter { id, llms });
+        Place(id)
+    }
+
+    /// The types this waiter may claim right now, in its own order of preference. None when no earlier
+    /// waiter stands in the way, so the request's own filter applies unchanged.
+    fn claimable<'a>(&self, llms: Option<&'a [&'a str]>) -> Option<Vec<&'a str>> {
+        let waiters = waiters().lock().unwrap();
+        let earlier: Vec<&Waiter> = waiters.queue.iter().take_while(|waiter| waiter.id != self.0).collect();
+        if earlier.is_empty() {
+            return None;
+        }
+        if earlier.iter().any(|waiter| waiter.llms.is_none()) {
+            return Some(Vec::new());
+        }
+        let wanted: Vec<&'a str> = match llms.filter(|llms| !llms.is_empty()) {
+            Some(llms) => llms.to_vec(),
+            None => providers::token_types(),
+        };
+        let promised = |token_type: &str| earlier.iter().flat_map(|waiter| waiter.llms.iter().flatten()).any(|llm| llm == token_type);
+        Some(wanted.into_iter().filter(|token_type| !promised(token_type)).collect())
+    }
+}
+
+impl Drop for Place {
+    fn drop(&mut self) {
+        waiters().lock().unwrap().queue.retain(|waiter| waiter.id != self.0);
+        departures().notify_waiters();
+    }
+}
+
+/// Claim the next token for `query`, waiting behind earlier requests for the same token types while every
+/// matching key is cooling down. Returns None if no token matches at all, or none came free within
+/// SAFE_TRIGGER_QUEUE_TIMEOUT_SECONDS.
+pub async fn next_token(query: TokenQuery<'_>) -> Result<Option<Token>> {
+    let place = Place::join(query.llms);
+
+    let timeout = Duration::from_secs(config::get().queue_timeout_seconds);
+    tokio::time::timeout(timeout, async {
+        loop {
+            // Registered before looking, so a waiter leaving in the meantime isn't missed
+            let departure = departures().notified();
+
+            let claimable = place.claimable(query.llms);
+            let wait = match claimable.as_deref() {
+                Some([]) => None, // Every type it accepts is promised to earlier waiters
+                types => {
+                    let narrowed = TokenQuery {
+                        llms: types.or(query.llms),
+                        // A request for any type keeps treating the types it may still claim equally
+                        strategy: if query.llms.is_none() && types.is_some() { LlmStrategy::Any } else { query.strategy },
+                        ..query
+                    };
+                    if let Some(token) = db_client::get_next_token_by_llms(narrowed)? {
+                        return Ok(Some(token));
+                    }
+                    db_client::token_availability(narrowed.llms)?.1
+                }
+            };
+            if db_client::token_availability(query.llms)?.0 == 0 {
+                return Ok(None); // Nothing matches, waiting won't help
+            }
+
+            let sleep_seconds = wait.map_or(MAX_SLEEP_SECONDS, |wait| (wait as u64).clamp(1, MAX_SLEEP_SECONDS));
+            debug!(wait_seconds = sleep_seconds, llms = ?query.llms, "Token pool exhausted, waiting for the next key");
+            tokio::select! {
+                _ = departure => {}
+                _ = tokio::time::sleep(Duration::from_secs(sleep_seconds)) => {}
+            }
+        }
+    })
+    .await
+    .unwrap_or(Ok(None))
+}
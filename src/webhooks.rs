@@ -0,0 +1,252 @@
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::{config, crypto, jobs, log_client};
+
+// Webhook callbacks for async jobs: once a job submitted with a callback_url finishes, its result
+// (the same JSON as GET /api/jobs/{id}) is POSTed there. Delivery is retried with exponential backoff
+// and its state is kept on the JOBS row and on the job's LOGS rows (whose request_id is the job ID).
+//
+// With a callback_secret, every delivery carries
+//   X-Safe-Trigger-Timestamp: unix seconds
+//   X-Safe-Trigger-Signature: sha256=<hex HMAC-SHA256 of "{timestamp}.{body}" keyed with the secret>
+//
+// Callback URLs come from whoever can submit jobs, so they may not point into the server's own network:
+// hosts resolving to loopback, private or link-local addresses are refused (unless listed in
+// SAFE_TRIGGER_CALLBACK_ALLOWED_HOSTS), and deliveries connect only to the addresses that were checked.
+
+const MAX_ATTEMPTS: u32 = 6;
+const FIRST_RETRY_DELAY_SECONDS: u64 = 10; // Doubled after every failed attempt
+const REQUEST_TIMEOUT_SECONDS: u64 = 10;
+
+pub const JOB_ID_HEADER: &str = "x-safe-trigger-job-id";
+pub const TIMESTAMP_HEADER: &str = "x-safe-trigger-timestamp";
+pub const SIGNATURE_HEADER: &str = "x-safe-trigger-signature";
+
+// Addresses no callback should reach: this machine, private networks, link-local (cloud metadata) and the like
+fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, ..] = v4.octets();
+            v4.is_loopback()
+                || v4.is_private()
+                || v4.is_link_local()
+                || v4.is_unspecified()
+                || v4.is_broadcast()
+                || v4.is_multicast()
+                || a == 0
+                || (a == 100 && (64..128).contains(&b)) // Carrier-grade NAT, 100.64.0.0/10
+                || (a == 198 && (b & 0xfe) == 18) // Benchmarking, 198.18.0.0/15
+        }
+        IpAddr::V6(v6) => {
+            // IPv4 addresses embedded in IPv6 reach the same hosts: mapped (::ffff:a.b.c.d), compatible
+            // (::a.b.c.d, which also covers :: and ::1) and NAT64 (64:ff9b::a.b.c.d)
+            if let Some(v4) = v6.to_ipv4() {
+                return is_internal(IpAddr::V4(v4));
+            }
+            let segments = v6.segments();
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                let [.., a, b, c, d] = v6.octets();
+                return is_internal(IpAddr::V4(Ipv4Addr::new(a, b, c, d)));
+            }
+            v6.is_multicast()
+                || (segments[0] & 0xfe00) == 0xfc00 // Unique local, fc00::/7
+                || (segments[0] & 0xffc0) == 0xfe80 // Link-local, fe80::/10
+        }
+    }
+}
+
+/// The addresses a callback to `url` may connect to. Empty for hosts in SAFE_TRIGGER_CALLBACK_ALLOWED_HOSTS,
+/// which are resolved as usual. Err carries the reason and whether it may be temporary (a DNS failure).
+pub async fn resolve_callback(url: &reqwest::Url) -> Result<Vec<SocketAddr>, (String, bool)> {
+    let host = url.host_str().ok_or_else(|| ("callback_url has no host".to_string(), false))?;
+    if config::get().callback_allowed_hosts.iter().any(|allowed| allowed.eq_ignore_ascii_case(host)) {
+        return Ok(Vec::new());
+    }
+    // IPv6 literals come bracketed
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = url.port_or_known_default().unwrap_or(443);
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|e| (format!("Failed to resolve callback host {}: {}", host, e), true))?
+        .collect();
+    if let Some(internal) = addrs.iter().find(|addr| is_internal(addr.ip())) {
+        return Err((format!("callback_url host {} resolves to the non-public address {}", host, internal.ip()), false));
+    }
+    if addrs.is_empty() {
+        return Err((format!("Callback host {} has no addresses", host), true));
+    }
+    Ok(addrs)
+}
+
+// A client that only connects to the checked addresses and doesn't follow redirects, which could lead anywhere
+async fn client_for(url: &str) -> Result<reqwest::Client, (String, bool)> {
+    let parsed = reqwest::Url::parse(url).map_err(|e| (format!("Invalid callback_url: {}", e), false))?;
+    let addrs = resolve_callback(&parsed).await?;
+    let mut builder = reqwest::Client::builder()
+        .timeout(Duration::from_secs(REQUEST_TIMEOUT_SECONDS))
+        .redirect(reqwest::redirect::Policy::none());
+    if let (Some(host), false) = (parsed.host_str(), addrs.is_empty()) {
+        builder = builder.resolve_to_addrs(host, &addrs);
+    }
+    builder.build().map_err(|e| (format!("Failed to build callback client: {}", e), true))
+}
+
+fn signature(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}.{}", timestamp, body).as_bytes());
+    format!("sha256={:x}", mac.finalize().into_bytes())
+}
+
+// One delivery attempt. Err carries the failure and whether retrying could help.
+async fn post(url: &str, job_id: &str, secret: Option<&str>, body: &str) -> Result<(), (String, bool)> {
+    // Resolved again on every attempt, the host's DNS may have changed since the job was submitted
+    let client = client_for(url).await?;
+    let timestamp = Utc::now().timestamp();
+    let mut request = client
+        .post(url)
+        .header("Content-Type", "application/json")
+        .header(JOB_ID_HEADER, job_id)
+        .header(TIMESTAMP_HEADER, timestamp.to_string())
+        .body(body.to_string());
+    if let Some(secret) = secret {
+        request = request.header(SIGNATURE_HEADER, signature(secret, timestamp, body));
+    }
+
+    match request.send().await {
+        Ok(response) if response.status().is_success() => Ok(()),
+        Ok(response) => {
+            let status = response.status();
+            // Other client errors mean the receiver rejected the payload, sending it again won't help
+            let retryable = status.is_server_error() || status.as_u16() == 408 || status.as_u16() == 429;
+            Err((format!("Callback returned HTTP {}", status), retryable))
+        }
+        Err(e) => Err((format!("Callback request failed: {}", e.without_url()), true)),
+    }
+}
+
+// Keep the JOBS row and the job's LOGS rows in step
+fn record_status(job_id: &str, status: &str, attempts: u32, error: Option<&str>) {
+    if let Err(e) = jobs::set_callback_status(job_id, status, attempts, error) {
+        error!(error = %e, "Failed to record callback status on job");
+    }
+    if let Err(e) = log_client::open_default().and_then(|logs| logs.set_callback_status(job_id, status, attempts)) {
+        error!(error = %e, "Failed to record callback status in logs");
+    }
+}
+
+/// Deliver a finished job's result to its callback_url, retrying until it is accepted or attempts run out.
+/// Does nothing for jobs without a pending callback.
+pub async fn deliver(job_id: String) {
+    let (callback, mut attempts) = match jobs::pending_callback(&job_id) {
+        Ok(Some(pending)) => pending,
+        Ok(None) => return,
+        Err(e) => {
+            error!(error = %e, "Failed to load job callback");
+            return;
+        }
+    };
+    let secret = match callback.secret.as_deref().map(crypto::reveal_token).transpose() {
+        Ok(secret) => secret,
+        Err(e) => return record_status(&job_id, "failed", attempts, Some(&e.0)),
+    };
+    let body = match jobs::get_job(&job_id) {
+        Ok(Some(job)) => match serde_json::to_value(&job) {
+            Ok(mut payload) => {
+                if let Some(fields) = payload.as_object_mut() {
+                    fields.remove("callback_status"); // Always "pending" at this point
+                }
+                payload.to_string()
+            }
+            Err(e) => {
+                error!(error = %e, "Failed to encode job callback payload");
+                return;
+            }
+        },
+        Ok(None) => return, // Pruned in the meantime
+        Err(e) => {
+            error!(error = %e, "Failed to load job for callback");
+            return;
+        }
+    };
+
+    let mut delay = FIRST_RETRY_DELAY_SECONDS << attempts.min(10);
+    while attempts < MAX_ATTEMPTS {
+        attempts += 1;
+        match post(&callback.url, &job_id, secret.as_deref(), &body).await {
+            Ok(()) => {
+                info!(attempts, "Job callback delivered");
+                return record_status(&job_id, "delivered", attempts, None);
+            }
+            Err((error, retryable)) if retryable && attempts < MAX_ATTEMPTS => {
+                warn!(attempts, error = %error, retry_in_seconds = delay, "Job callback failed, will retry");
+                record_status(&job_id, "pending", attempts, Some(&error));
+                tokio::time::sleep(Duration::from_secs(delay)).await;
+                delay *= 2;
+            }
+            Err((error, _)) => {
+                warn!(attempts, error = %error, "Job callback failed, giving up");
+                return record_status(&job_id, "failed", attempts, Some(&error));
+            }
+        }
+    }
+    // Attempts were used up before a restart interrupted the last retry
+    record_status(&job_id, "failed", attempts, Some("Out of delivery attempts"));
+}
+
+/// Restart deliveries that were still pending when the server stopped.
+pub fn resume_pending_deliveries() {
+    match jobs::pending_callback_job_ids() {
+        Ok(ids) => {
+            if !ids.is_empty() {
+                info!(count = ids.len(), "Resuming pending job callbacks");
+            }
+            for id in ids {
+                let span = info_span!("job_callback", request_id = %id);
+                tokio::spawn(deliver(id).instrument(span));
+            }
+        }
+        Err(e) => warn!(error = %e, "Failed to load pending job callbacks"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn internal(ip: &str) -> bool {
+        is_internal(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn internal_ipv4_ranges_are_refused() {
+        for ip in ["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "0.0.0.0", "100.64.0.1", "198.18.0.1", "198.19.255.255", "224.0.0.1", "255.255.255.255"] {
+            assert!(internal(ip), "{} should be internal", ip);
+        }
+        for ip in ["8.8.8.8", "100.128.0.1", "198.17.255.255", "198.20.0.1"] {
+            assert!(!internal(ip), "{} should be public", ip);
+        }
+    }
+
+    #[test]
+    fn internal_ipv6_ranges_are_refused() {
+        for ip in ["::1", "::", "fc00::1", "fd12:3456::1", "fe80::1", "ff02::1"] {
+            assert!(internal(ip), "{} should be internal", ip);
+        }
+        assert!(!internal("2001:4860:4860::8888"));
+    }
+
+    #[test]
+    fn embedded_ipv4_addresses_are_checked_as_ipv4() {
+        for ip in ["::ffff:127.0.0.1", "::ffff:169.254.169.254", "::127.0.0.1", "::10.0.0.1", "64:ff9b::127.0.0.1", "64:ff9b::a9fe:a9fe", "64:ff9b::198.18.0.1"] {
+            assert!(internal(ip), "{} should be internal", ip);
+        }
+        for ip in ["::ffff:8.8.8.8", "::8.8.8.8", "64:ff9b::8.8.8.8"] {
+            assert!(!internal(ip), "{} should be public", ip);
+        }
+    }
+}
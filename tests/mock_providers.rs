@@ -0,0 +1,720 @@
+// End-to-end tests: the real binary runs in a scratch directory with its own data.db, pointed at an
+// in-process mock of the Gemini and OpenRouter APIs through SAFE_TRIGGER_*_BASE_URL, and is driven
+// through /api/chat, or run as `safe-trigger batch` for file runs.
+//
+// The mock answers according to the API key it receives:
+//   ok-*          a normal answer echoing the prompt
+//   json-*        the prompt itself as the answer, for structured output
+//   slow-*        a normal answer after half a second, so identical requests overlap
+//   rate-limited  429 Too Many Requests
+//   broken        500 Internal Server Error
+//   garbled       200 with a body that isn't JSON
+//
+// OpenRouter answers 400 for the model "no-such-model", as it does for model IDs it doesn't know.
+// The mock also serves Ollama's /ollama/api/chat, recorded under the key "ollama", and takes job
+// callbacks at /callback, recorded under the key "callback".
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::post,
+    Json, Router,
+};
+use rusqlite::{params, Connection};
+use serde_json::{json, Value};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Clone, Default)]
+struct Calls {
+    keys: Arc<Mutex<Vec<String>>>,   // API keys in the order requests arrived
+    bodies: Arc<Mutex<Vec<Value>>>,  // Request bodies, in the same order
+}
+
+impl Calls {
+    fn record(&self, key: &str, body: &Value) {
+        self.keys.lock().unwrap().push(key.to_string());
+        self.bodies.lock().unwrap().push(body.clone());
+    }
+
+    fn keys(&self) -> Vec<String> {
+        self.keys.lock().unwrap().clone()
+    }
+
+    fn last_body(&self) -> Value {
+        self.bodies.lock().unwrap().last().cloned().expect("no request reached the mock")
+    }
+}
+
+struct MockProviders {
+    base_url: String,
+    calls: Calls,
+}
+
+// The response for `key`, with `answer` for the keys that succeed
+fn mock_response(key: &str, answer: Value) -> Response {
+    match key {
+        "rate-limited" => (StatusCode::TOO_MANY_REQUESTS, "slow down").into_response(),
+        "broken" => (StatusCode::INTERNAL_SERVER_ERROR, "upstream exploded").into_response(),
+        "garbled" => (StatusCode::OK, "<html>not json</html>").into_response(),
+        _ => Json(answer).into_response(),
+    }
+}
+
+async fn mock_gemini(State(calls): State<Calls>, headers: HeaderMap, Json(body): Json<Value>) -> Response {
+    let key = headers.get("x-goog-api-key").and_then(|v| v.to_str().ok()).unwrap_or_default().to_string();
+    calls.record(&key, &body);
+    if key.starts_with("slow-") {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+    // Like the real API, the OpenAPI-subset responseSchema refuses JSON Schema keywords
+    if let Some(schema) = body.pointer("/generationConfig/responseSchema") {
+        let schema = schema.to_string();
+        if ["additionalProperties", "$ref", "$defs", "$schema"].iter().any(|keyword| schema.contains(keyword)) {
+            return (StatusCode::BAD_REQUEST, "Invalid JSON payload received. Unknown name in responseSchema").into_response();
+        }
+    }
+    let prompt = body.pointer("/contents/0/parts/0/text").and_then(Value::as_str).unwrap_or_default();
+    let text = if key.starts_with("json-") { prompt.to_string() } else { format!("gemini: {}", prompt) };
+    let answer = json!({ "candidates": [ { "content": { "parts": [ { "text": text } ] } } ] });
+    mock_response(&key, answer)
+}
+
+async fn mock_callback(State(calls): State<Calls>, Json(body): Json<Value>) -> StatusCode {
+    calls.record("callback", &body);
+    StatusCode::OK
+}
+
+async fn mock_openrouter(State(calls): State<Calls>, headers: HeaderMap, Json(body): Json<Value>) -> Response {
+    let key = headers
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .unwrap_or_default()
+        .to_string();
+    calls.record(&key, &body);
+    if body["model"] == "no-such-model" {
+        return (StatusCode::BAD_REQUEST, r#"{"error":{"message":"no-such-model is not a valid model ID","code":400}}"#).into_response();
+    }
+    let prompt = body.pointer("/messages/1/content").and_then(Value::as_str).unwrap_or_default();
+    let text = if key.starts_with("json-") { prompt.to_string() } else { format!("openrouter: {}", prompt) };
+    let answer = json!({ "choices": [ { "message": { "role": "assistant", "content": text } } ] });
+    mock_response(&key, answer)
+}
+
+async fn mock_ollama(State(calls): State<Calls>, Json(body): Json<Value>) -> Response {
+    calls.record("ollama", &body);
+    let prompt = body["messages"].as_array().and_then(|messages| messages.last()).and_then(|message| message["content"].as_str()).unwrap_or_default();
+    Json(json!({ "message": { "role": "assistant", "content": format!("ollama: {}", prompt) } })).into_response()
+}
+
+fn start_mock_providers() -> MockProviders {
+    let calls = Calls::default();
+    let app = Router::new()
+        .route("/gemini/models/:call", post(mock_gemini))
+        .route("/openrouter/chat/completions", post(mock_openrouter))
+        .route("/ollama/api/chat", post(mock_ollama))
+        .route("/callback", post(mock_callback))
+        .with_state(calls.clone());
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service()));
+    MockProviders { base_url, calls }
+}
+
+struct SafeTrigger {
+    child: Child,
+    dir: PathBuf,
+    url: String,
+}
+
+// The binary, run in `dir` against the mock, with quiet output
+fn command(mock: &MockProviders, dir: &Path, max_attempts: u32) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_safe-trigger"));
+    command
+        .current_dir(dir)
+        .env("SAFE_TRIGGER_GEMINI_BASE_URL", format!("{}/gemini", mock.base_url))
+        .env("SAFE_TRIGGER_OPENROUTER_BASE_URL", format!("{}/openrouter", mock.base_url))
+        .env("SAFE_TRIGGER_MAX_RETRY_ATTEMPTS", max_attempts.to_string())
+        .env("SAFE_TRIGGER_RETRY_DELAY_SECONDS", "0")
+        .env("SAFE_TRIGGER_QUEUE_TIMEOUT_SECONDS", "0")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    command
+}
+
+impl SafeTrigger {
+    /// Start the server with `tokens` as (key, token_type) rows, allowing `max_attempts` tokens per request.
+    async fn start(mock: &MockProviders, tokens: &[(&str, &str)], max_attempts: u32) -> Self {
+        Self::start_with(mock, tokens, max_attempts, &[], &[]).await
+    }
+
+    /// Like `start`, with extra (name, contents) files in the server's working directory and extra environment variables.
+    async fn start_with(
+        mock: &MockProviders,
+        tokens: &[(&str, &str)],
+        max_attempts: u32,
+        files: &[(&str, &str)],
+        env: &[(&str, &str)],
+    ) -> Self {
+        let dir = std::env::temp_dir().join(format!("safe-trigger-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, contents) in files {
+            std::fs::write(dir.join(name), contents).unwrap();
+        }
+        let db = Connection::open(dir.join("data.db")).unwrap();
+        db.execute_batch(
+            "CREATE TABLE TOKENS (
+                id INTEGER NOT NULL PRIMARY KEY,
+                token TEXT,
+                token_type TEXT,
+                delay_by_second INTEGER DEFAULT 20,
+                triggered_on INTEGER,
+                trouble_delay INTEGER DEFAULT 0
+            );",
+        )
+        .unwrap();
+        for (key, token_type) in tokens {
+            db.execute(
+                "INSERT INTO TOKENS (token, token_type, delay_by_second) VALUES (?1, ?2, 0)",
+                params![key, token_type],
+            )
+            .unwrap();
+        }
+        drop(db);
+
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let child = command(mock, &dir, max_attempts)
+            .env("SAFE_TRIGGER_PORT", port.to_string())
+            .envs(env.iter().copied())
+            .spawn()
+            .unwrap();
+        let server = SafeTrigger { child, dir, url: format!("http://127.0.0.1:{}", port) };
+
+        for _ in 0..100 {
+            if reqwest::get(format!("{}/healthz", server.url)).await.is_ok() {
+                return server;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("safe-trigger did not start listening on port {}", port);
+    }
+
+    async fn chat(&self, prompt: &str, llm: &str) -> Value {
+        self.chat_with(json!({ "prompt": prompt, "system_prompt": "Be brief.", "llm": llm })).await
+    }
+
+    async fn chat_with(&self, body: Value) -> Value {
+        self.post("/api/chat", body).await
+    }
+
+    async fn post(&self, path: &str, body: Value) -> Value {
+        reqwest::Client::new()
+            .post(format!("{}{}", self.url, path))
+            .json(&body)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap()
+    }
+
+    async fn get(&self, path: &str) -> String {
+        reqwest::get(format!("{}{}", self.url, path)).await.unwrap().text().await.unwrap()
+    }
+
+    fn in_trouble(&self, key: &str) -> bool {
+        let db = Connection::open(self.dir.join("data.db")).unwrap();
+        db.query_row("SELECT trouble_delay FROM TOKENS WHERE token = ?1", params![key], |row| row.get::<_, i64>(0))
+            .unwrap()
+            == 1
+    }
+}
+
+impl Drop for SafeTrigger {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn error_of(response: &Value) -> &str {
+    response.pointer("/Err/error").and_then(Value::as_str).unwrap_or_else(|| panic!("expected an error, got {}", response))
+}
+
+#[tokio::test]
+async fn gemini_success() {
+    let mock = start_mock_providers();
+    let server = SafeTrigger::start(&mock, &[("ok-gemini", "gemini")], 1).await;
+
+    let response = server.chat("hello", "gemini").await;
+    assert_eq!(response["Ok"]["content"], "gemini: hello");
+    assert_eq!(response["Ok"]["token_type"], "gemini");
+    assert_eq!(mock.calls.keys(), ["ok-gemini"]);
+}
+
+#[tokio::test]
+async fn openrouter_success() {
+    let mock = start_mock_providers();
+    let server = SafeTrigger::start(&mock, &[("ok-openrouter", "openrouter")], 1).await;
+
+    let response = server.chat("hello", "openrouter").await;
+    assert_eq!(response["Ok"]["content"], "openrouter: hello");
+    assert_eq!(response["Ok"]["token_type"], "openrouter");
+}
+
+#[tokio::test]
+async fn rate_limited_token_is_marked_in_trouble() {
+    let mock = start_mock_providers();
+    let server = SafeTrigger::start(&mock, &[("rate-limited", "gemini")], 1).await;
+
+    let response = server.chat("hello", "gemini").await;
+    assert!(error_of(&response).contains("429"), "unexpected error: {}", response);
+    assert!(server.in_trouble("rate-limited"));
+}
+
+#[tokio::test]
+async fn server_error_is_reported() {
+    let mock = start_mock_providers();
+    let server = SafeTrigger::start(&mock, &[("broken", "openrouter")], 1).await;
+
+    let response = server.chat("hello", "openrouter").await;
+    let error = error_of(&response);
+    assert!(error.contains("500") && error.contains("upstream exploded"), "unexpected error: {}", error);
+    assert!(server.in_trouble("broken"));
+}
+
+#[tokio::test]
+async fn malformed_json_is_reported() {
+    let mock = start_mock_providers();
+    let server = SafeTrigger::start(&mock, &[("garbled", "gemini")], 1).await;
+
+    let response = server.chat("hello", "gemini").await;
+    assert!(error_of(&response).contains("Failed to parse JSON response"), "unexpected error: {}", response);
+}
+
+#[tokio::test]
+async fn failed_token_switches_to_another_of_the_same_type() {
+    let mock = start_mock_providers();
+    let server = SafeTrigger::start(&mock, &[("broken", "gemini"), ("ok-gemini", "gemini")], 2).await;
+
+    let response = server.chat("hello", "gemini").await;
+    assert_eq!(response["Ok"]["content"], "gemini: hello");
+    assert_eq!(mock.calls.keys(), ["broken", "ok-gemini"]);
+    assert!(server.in_trouble("broken"));
+}
+
+#[tokio::test]
+async fn failed_token_switches_to_the_next_provider() {
+    let mock = start_mock_providers();
+    let server = SafeTrigger::start(&mock, &[("rate-limited", "gemini"), ("ok-openrouter", "openrouter")], 2).await;
+
+    let response = server.chat("hello", "gemini,openrouter").await;
+    assert_eq!(response["Ok"]["content"], "openrouter: hello");
+    assert_eq!(response["Ok"]["token_type"], "openrouter");
+    assert_eq!(mock.calls.keys(), ["rate-limited", "ok-openrouter"]);
+}
+
+#[tokio::test]
+async fn no_retry_beyond_max_attempts() {
+    let mock = start_mock_providers();
+    let server = SafeTrigger::start(&mock, &[("broken", "gemini"), ("ok-gemini", "gemini")], 1).await;
+
+    let response = server.chat("hello", "gemini").await;
+    assert!(error_of(&response).contains("Max retry attempts (1) reached"), "unexpected error: {}", response);
+    assert_eq!(mock.calls.keys(), ["broken"]);
+}
+
+#[tokio::test]
+async fn generation_parameters_are_mapped_per_provider() {
+    let mock = start_mock_providers();
+    let server = SafeTrigger::start(&mock, &[("ok-gemini", "gemini"), ("ok-openrouter", "openrouter")], 1).await;
+    let params = json!({ "temperature": 0.3, "max_tokens": 200, "top_p": 0.9, "stop": ["END"] });
+
+    let mut request = json!({ "prompt": "hello", "system_prompt": "Be brief.", "llm": "gemini" });
+    request.as_object_mut().unwrap().extend(params.as_object().unwrap().clone());
+    server.chat_with(request.clone()).await;
+    let config = &mock.calls.last_body()["generationConfig"];
+    assert_eq!(config["temperature"], 0.3);
+    assert_eq!(config["maxOutputTokens"], 200);
+    assert_eq!(config["topP"], 0.9);
+    assert_eq!(config["stopSequences"], json!(["END"]));
+
+    request["llm"] = json!("openrouter");
+    server.chat_with(request).await;
+    let body = mock.calls.last_body();
+    assert_eq!(body["temperature"], 0.3);
+    assert_eq!(body["max_tokens"], 200);
+    assert_eq!(body["top_p"], 0.9);
+    assert_eq!(body["stop"], json!(["END"]));
+}
+
+#[tokio::test]
+async fn model_defaults_fill_in_unset_parameters() {
+    let mock = start_mock_providers();
+    let defaults = r#"{ "deepseek/deepseek-chat": { "temperature": 0.1, "max_tokens": 64 } }"#;
+    let server = SafeTrigger::start_with(&mock, &[("ok-openrouter", "openrouter")], 1, &[("model_defaults.json", defaults)], &[]).await;
+
+    server.chat_with(json!({ "prompt": "hello", "system_prompt": "", "llm": "openrouter", "temperature": 0.7 })).await;
+    let body = mock.calls.last_body();
+    assert_eq!(body["temperature"], 0.7);
+    assert_eq!(body["max_tokens"], 64);
+    assert!(body.get("top_p").is_none());
+}
+
+#[tokio::test]
+async fn invalid_generation_parameters_are_rejected() {
+    let mock = start_mock_providers();
+    let server = SafeTrigger::start(&mock, &[("ok-gemini", "gemini")], 1).await;
+
+    let response = server.chat_with(json!({ "prompt": "hello", "system_prompt": "", "temperature": 3.5 })).await;
+    assert!(error_of(&response).contains("temperature must be between 0 and 2"), "unexpected error: {}", response);
+    let response = server.chat_with(json!({ "prompt": "hello", "system_prompt": "", "top_p": 0 })).await;
+    assert!(error_of(&response).contains("top_p"), "unexpected error: {}", response);
+    assert!(mock.calls.keys().is_empty());
+}
+
+fn person_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": { "name": { "type": "string" }, "age": { "type": "integer" } },
+        "required": ["name", "age"]
+    })
+}
+
+#[tokio::test]
+async fn response_schema_is_sent_and_answer_parsed() {
+    let mock = start_mock_providers();
+    let server = SafeTrigger::start(&mock, &[("json-gemini", "gemini"), ("json-openrouter", "openrouter")], 1).await;
+    let prompt = r#"{"name": "Ada", "age": 36}"#;
+
+    let response = server
+        .chat_with(json!({ "prompt": prompt, "system_prompt": "", "llm": "gemini", "response_schema": person_schema() }))
+        .await;
+    assert_eq!(response["Ok"]["json"], json!({ "name": "Ada", "age": 36 }));
+    let config = &mock.calls.last_body()["generationConfig"];
+    assert_eq!(config["responseMimeType"], "application/json");
+    assert_eq!(config["responseJsonSchema"], person_schema());
+
+    let response = server
+        .chat_with(json!({ "prompt": prompt, "system_prompt": "", "llm": "openrouter", "response_schema": person_schema() }))
+        .await;
+    assert_eq!(response["Ok"]["json"], json!({ "name": "Ada", "age": 36 }));
+    let format = &mock.calls.last_body()["response_format"];
+    assert_eq!(format["type"], "json_schema");
+    assert_eq!(format["json_schema"]["schema"], person_schema());
+}
+
+#[tokio::test]
+async fn full_json_schema_reaches_gemini() {
+    let mock = start_mock_providers();
+    let server = SafeTrigger::start(&mock, &[("json-gemini", "gemini")], 1).await;
+    let mut schema = person_schema();
+    schema["additionalProperties"] = json!(false);
+
+    let response = server
+        .chat_with(json!({ "prompt": r#"{"name": "Ada", "age": 36}"#, "system_prompt": "", "llm": "gemini", "response_schema": schema }))
+        .await;
+    assert_eq!(response["Ok"]["json"], json!({ "name": "Ada", "age": 36 }));
+    assert_eq!(mock.calls.last_body()["generationConfig"]["responseJsonSchema"], schema);
+    assert!(!server.in_trouble("json-gemini"));
+}
+
+#[tokio::test]
+async fn answer_not_matching_schema_retries_without_trouble() {
+    let mock = start_mock_providers();
+    let server = SafeTrigger::start(&mock, &[("ok-gemini", "gemini"), ("json-gemini", "gemini")], 2).await;
+
+    let response = server
+        .chat_with(json!({ "prompt": r#"{"name": "Ada", "age": 36}"#, "system_prompt": "", "llm": "gemini", "response_schema": person_schema() }))
+        .await;
+    assert_eq!(response["Ok"]["json"]["name"], "Ada");
+    assert_eq!(mock.calls.keys(), ["ok-gemini", "json-gemini"]);
+    assert!(!server.in_trouble("ok-gemini"));
+
+    // Valid JSON of the wrong shape is refused too
+    let server = SafeTrigger::start(&mock, &[("json-gemini", "gemini")], 1).await;
+    let response = server
+        .chat_with(json!({ "prompt": r#"{"name": "Ada"}"#, "system_prompt": "", "llm": "gemini", "response_schema": person_schema() }))
+        .await;
+    assert!(error_of(&response).contains("does not match response_schema"), "unexpected error: {}", response);
+}
+
+#[tokio::test]
+async fn invalid_response_schema_is_rejected() {
+    let mock = start_mock_providers();
+    let server = SafeTrigger::start(&mock, &[("json-gemini", "gemini")], 1).await;
+
+    let response = server
+        .chat_with(json!({ "prompt": "hello", "system_prompt": "", "response_schema": { "type": "no-such-type" } }))
+        .await;
+    assert!(error_of(&response).contains("response_schema is not a valid JSON Schema"), "unexpected error: {}", response);
+    assert!(mock.calls.keys().is_empty());
+}
+
+#[tokio::test]
+async fn callbacks_to_internal_addresses_are_refused() {
+    let mock = start_mock_providers();
+    let server = SafeTrigger::start(&mock, &[("ok-gemini", "gemini")], 1).await;
+
+    for url in ["http://127.0.0.1:8080/hook", "http://169.254.169.254/latest/meta-data", "http://10.0.0.5/", "http://[::1]/hook", "http://localhost/hook"] {
+        let response = server
+            .post("/api/jobs", json!({ "prompt": "hello", "system_prompt": "", "callback_url": url }))
+            .await;
+        assert!(error_of(&response).contains("non-public address"), "{} was accepted: {}", url, response);
+    }
+    assert!(mock.calls.keys().is_empty());
+}
+
+#[tokio::test]
+async fn callbacks_reach_allowed_hosts() {
+    let mock = start_mock_providers();
+    let env = [("SAFE_TRIGGER_CALLBACK_ALLOWED_HOSTS", "127.0.0.1")];
+    let server = SafeTrigger::start_with(&mock, &[("ok-gemini", "gemini")], 1, &[], &env).await;
+
+    let response = server
+        .post("/api/jobs", json!({ "prompt": "hello", "system_prompt": "", "callback_url": format!("{}/callback", mock.base_url) }))
+        .await;
+    let job_id = response["Ok"]["job_id"].as_str().unwrap_or_else(|| panic!("job refused: {}", response)).to_string();
+    for _ in 0..100 {
+        if mock.calls.keys().contains(&"callback".to_string()) {
+            let delivered = mock.calls.last_body();
+            assert_eq!(delivered["id"], job_id.as_str());
+            assert_eq!(delivered["result"]["content"], "gemini: hello");
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("the callback was never delivered");
+}
+
+#[tokio::test]
+async fn waiting_requests_are_served_in_arrival_order() {
+    let mock = start_mock_providers();
+    let env = [("SAFE_TRIGGER_QUEUE_TIMEOUT_SECONDS", "60")];
+    let server = Arc::new(SafeTrigger::start_with(&mock, &[("ok-gemini", "gemini")], 1, &[], &env).await);
+    // A single key, busy for a second after every use
+    let db = Connection::open(server.dir.join("data.db")).unwrap();
+    db.execute("UPDATE TOKENS SET delay_by_second = 1", []).unwrap();
+
+    // Different llm lists that can all use the Gemini key, arriving one after another
+    let mut requests = Vec::new();
+    for (prompt, llm) in [("first", Some("gemini")), ("second", Some("gemini")), ("third", Some("openrouter,gemini")), ("fourth", None), ("fifth", Some("gemini"))] {
+        let server = server.clone();
+        let mut body = json!({ "prompt": prompt, "system_prompt": "" });
+        if let Some(llm) = llm {
+            body["llm"] = json!(llm);
+        }
+        requests.push(tokio::spawn(async move { server.chat_with(body).await }));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+    for request in requests {
+        let response = request.await.unwrap();
+        assert!(response["Ok"]["content"].is_string(), "unexpected response: {}", response);
+    }
+
+    let served: Vec<Value> = mock.calls.bodies.lock().unwrap().iter().map(|body| body["contents"][0]["parts"][0]["text"].clone()).collect();
+    assert_eq!(served, ["first", "second", "third", "fourth", "fifth"]);
+}
+
+#[tokio::test]
+async fn requests_sharing_an_in_flight_call_are_logged() {
+    let mock = start_mock_providers();
+    let server = Arc::new(SafeTrigger::start(&mock, &[("slow-gemini", "gemini")], 1).await);
+
+    let requests: Vec<_> = ["first", "second"]
+        .into_iter()
+        .map(|caller| {
+            let server = server.clone();
+            tokio::spawn(async move { server.chat_with(json!({ "prompt": "hello", "system_prompt": "", "caller": caller })).await })
+        })
+        .collect();
+    for request in requests {
+        assert_eq!(request.await.unwrap()["Ok"]["content"], "gemini: hello");
+    }
+    assert_eq!(mock.calls.keys(), ["slow-gemini"]);
+
+    let db = Connection::open(server.dir.join("data.db")).unwrap();
+    let mut stmt = db.prepare("SELECT caller, COALESCE(shared, 0), request_id IS NOT NULL FROM LOGS ORDER BY caller").unwrap();
+    let rows: Vec<(String, bool, bool)> = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    // Either request may have reached the provider first
+    assert_eq!(rows.len(), 2, "unexpected LOGS rows: {:?}", rows);
+    assert_eq!(rows.iter().filter(|(_, shared, _)| *shared).count(), 1);
+    assert!(rows.iter().all(|(_, _, has_request_id)| *has_request_id));
+    assert_eq!(rows.iter().map(|(caller, _, _)| caller.as_str()).collect::<Vec<_>>(), ["first", "second"]);
+}
+
+#[tokio::test]
+async fn requests_with_other_selection_dont_share_an_in_flight_call() {
+    let mock = start_mock_providers();
+    let server = Arc::new(SafeTrigger::start(&mock, &[("slow-gemini-1", "gemini"), ("slow-gemini-2", "gemini")], 1).await);
+
+    let requests: Vec<_> = ["lru", "round_robin"]
+        .into_iter()
+        .map(|selection| {
+            let server = server.clone();
+            tokio::spawn(async move { server.chat_with(json!({ "prompt": "hello", "system_prompt": "", "selection": selection })).await })
+        })
+        .collect();
+    for request in requests {
+        assert_eq!(request.await.unwrap()["Ok"]["content"], "gemini: hello");
+    }
+    assert_eq!(mock.calls.keys().len(), 2);
+}
+
+#[tokio::test]
+async fn busy_ollama_tokens_are_reported_available() {
+    let mock = start_mock_providers();
+    let files = [("admin_token.txt", "admin")];
+    let server = SafeTrigger::start_with(&mock, &[("", "ollama"), ("ok-gemini", "gemini")], 1, &files, &[]).await;
+    // Both just used, with a long cooldown that Ollama tokens don't sit out
+    let db = Connection::open(server.dir.join("data.db")).unwrap();
+    db.execute("UPDATE TOKENS SET triggered_on = strftime('%s', 'now'), delay_by_second = 600", []).unwrap();
+
+    let tokens: Value = serde_json::from_str(&server.get("/admin/tokens?admin_token=admin").await).unwrap();
+    let availability: Vec<(Value, Value)> = tokens["Ok"]["tokens"]
+        .as_array()
+        .unwrap_or_else(|| panic!("unexpected response: {}", tokens))
+        .iter()
+        .map(|token| (token["available"].clone(), token["seconds_until_available"].clone()))
+        .collect();
+    assert_eq!(availability[0], (json!(true), json!(0)));
+    assert_eq!(availability[1].0, json!(false));
+
+    let metrics = server.get("/metrics").await;
+    let ollama: Vec<&str> = metrics.lines().filter(|line| line.starts_with("safe_trigger_tokens{") && line.contains(r#"token_type="ollama""#)).collect();
+    assert_eq!(ollama, [r#"safe_trigger_tokens{state="available",token_type="ollama"} 1"#]);
+}
+
+#[tokio::test]
+async fn busy_ollama_tokens_are_still_handed_out() {
+    let mock = start_mock_providers();
+    let server = SafeTrigger::start(&mock, &[("", "ollama")], 1).await;
+    let db = Connection::open(server.dir.join("data.db")).unwrap();
+    db.execute(
+        "UPDATE TOKENS SET base_url = ?1, triggered_on = strftime('%s', 'now'), delay_by_second = 600",
+        params![format!("{}/ollama", mock.base_url)],
+    )
+    .unwrap();
+
+    for prompt in ["first", "second"] {
+        let response = server.chat_with(json!({ "prompt": prompt, "system_prompt": "", "llm": "ollama", "model": "llama3" })).await;
+        assert_eq!(response["Ok"]["content"], format!("ollama: {}", prompt), "unexpected response: {}", response);
+    }
+    assert_eq!(mock.calls.keys(), ["ollama", "ollama"]);
+}
+
+#[tokio::test]
+async fn model_overrides_apply_to_their_token_type_only() {
+    let mock = start_mock_providers();
+    let server = SafeTrigger::start(&mock, &[("rate-limited", "gemini"), ("ok-openrouter", "openrouter")], 2).await;
+
+    let response = server
+        .chat_with(json!({ "prompt": "hello", "system_prompt": "", "llm": "gemini,openrouter", "model": "gemini:gemini-2.0-flash" }))
+        .await;
+    assert_eq!(response["Ok"]["token_type"], "openrouter");
+    assert_eq!(mock.calls.last_body()["model"], "deepseek/deepseek-chat");
+
+    // Cooldowns end on the next second, even with delay_by_second 0
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let response = server
+        .chat_with(json!({ "prompt": "hello again", "system_prompt": "", "llm": "openrouter", "model": "openai/gpt-4o" }))
+        .await;
+    assert!(response["Ok"].is_object(), "unexpected response: {}", response);
+    assert_eq!(mock.calls.last_body()["model"], "openai/gpt-4o");
+
+    // A bare model is ambiguous when several types may serve the request
+    let response = server
+        .chat_with(json!({ "prompt": "hello", "system_prompt": "", "llm": "gemini,openrouter", "model": "gemini-2.0-flash" }))
+        .await;
+    assert!(error_of(&response).contains("needs a token type"), "unexpected error: {}", response);
+}
+
+#[tokio::test]
+async fn unknown_model_does_not_put_the_key_in_trouble() {
+    let mock = start_mock_providers();
+    let server = SafeTrigger::start(&mock, &[("ok-openrouter", "openrouter")], 1).await;
+
+    let response = server
+        .chat_with(json!({ "prompt": "hello", "system_prompt": "", "llm": "openrouter", "model": "no-such-model" }))
+        .await;
+    assert!(error_of(&response).contains("not a valid model ID"), "unexpected error: {}", response);
+    assert!(!server.in_trouble("ok-openrouter"));
+}
+
+#[tokio::test]
+async fn batch_prompts_share_the_other_chat_fields() {
+    let mock = start_mock_providers();
+    let server = SafeTrigger::start(&mock, &[("ok-openrouter-1", "openrouter"), ("ok-openrouter-2", "openrouter")], 1).await;
+
+    let response = server
+        .post("/api/batch", json!({ "prompts": ["one", "two"], "system_prompt": "", "llm": "openrouter", "model": "openai/gpt-4o", "temperature": 0.5 }))
+        .await;
+    let contents: Vec<&Value> = response["Ok"]["results"]
+        .as_array()
+        .unwrap_or_else(|| panic!("unexpected response: {}", response))
+        .iter()
+        .map(|result| &result["Ok"]["content"])
+        .collect();
+    assert_eq!(contents, [&json!("openrouter: one"), &json!("openrouter: two")]);
+    assert_eq!(mock.calls.last_body()["model"], "openai/gpt-4o");
+    assert_eq!(mock.calls.last_body()["temperature"], 0.5);
+
+    let response = server.chat_with(json!({ "system_prompt": "", "llm": "openrouter" })).await;
+    assert_eq!(error_of(&response), "A prompt is required");
+}
+
+#[tokio::test]
+async fn interrupted_file_batch_resumes_without_duplicates() {
+    let mock = start_mock_providers();
+    let input: String = (1..=6).map(|i| format!("{}\n", json!({ "id": i, "prompt": format!("prompt {}", i), "system_prompt": "" }))).collect();
+    let files = [("input.jsonl", input.as_str())];
+    let server = SafeTrigger::start_with(&mock, &[("slow-gemini", "gemini")], 1, &files, &[]).await;
+    let output = server.dir.join("output.jsonl");
+    let batch = || {
+        let mut batch = tokio::process::Command::from(command(&mock, &server.dir, 1));
+        batch.args(["batch", "input.jsonl", "output.jsonl"]).kill_on_drop(true);
+        batch
+    };
+
+    // One key answering slowly, so the run can be stopped after a couple of records
+    let mut first_run = batch().spawn().unwrap();
+    let mut records = 0;
+    for _ in 0..200 {
+        records = std::fs::read_to_string(&output).unwrap_or_default().lines().count();
+        if records >= 2 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    first_run.kill().await.unwrap();
+    assert!((2..6).contains(&records), "expected a partial output, got {} records", records);
+
+    // A record left half-written by the crash
+    let mut partial = std::fs::read_to_string(&output).unwrap();
+    partial.push_str(r#"{"content": "gemini: prom"#);
+    std::fs::write(&output, partial).unwrap();
+
+    assert!(batch().status().await.unwrap().success());
+    let mut lines: Vec<u64> = std::fs::read_to_string(&output)
+        .unwrap()
+        .lines()
+        .map(|line| {
+            let record: Value = serde_json::from_str(line).unwrap_or_else(|e| panic!("bad record {:?}: {}", line, e));
+            assert_eq!(record["content"], format!("gemini: prompt {}", record["id"]), "unexpected record: {}", record);
+            record["line"].as_u64().unwrap()
+        })
+        .collect();
+    lines.sort();
+    assert_eq!(lines, [1, 2, 3, 4, 5, 6]);
+}